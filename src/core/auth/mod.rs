@@ -0,0 +1,183 @@
+pub mod oauth2;
+pub mod sigv4;
+
+use std::{fmt, time::SystemTime};
+
+use actix_web::http::{uri::PathAndQuery, Method, Uri};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use percent_encoding::utf8_percent_encode;
+use sha2::Sha256;
+
+use crate::config::{
+    auth::{AuthConfig, SignatureEncoding},
+    request_mapping::COMPONENT,
+    secret::Secret,
+};
+use crate::routes::evaluate::EvaluateError;
+
+#[derive(Debug)]
+pub enum AuthError {
+    UnresolvedSecret(&'static str),
+    InvalidUri(String),
+    InvalidToken(String),
+    /* The token endpoint failed, which says nothing about the service the token is for. */
+    TokenRequest(Box<EvaluateError>),
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::UnresolvedSecret(field) => write!(f, "auth {} is not resolved", field),
+            AuthError::InvalidUri(err) => write!(f, "unable to add API key to uri: {}", err),
+            AuthError::InvalidToken(err) => write!(f, "invalid token response: {}", err),
+            AuthError::TokenRequest(err) => write!(f, "token request failed: {}", err),
+            AuthError::Unsupported(what) => write!(f, "{} is not supported here", what),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn expose<'a>(secret: &'a Secret, field: &'static str) -> Result<&'a str, AuthError> {
+    secret.expose().ok_or(AuthError::UnresolvedSecret(field))
+}
+
+/* Authenticate a request to a service, adding headers and returning the uri to call. `body` is
+ * the exact bytes that will be sent, which is what HMAC and SigV4 sign.
+ */
+pub fn authenticate(
+    auth: &AuthConfig,
+    method: &Method,
+    uri: Uri,
+    headers: &mut Vec<(String, String)>,
+    body: &[u8],
+) -> Result<Uri, AuthError> {
+    match auth {
+        AuthConfig::Bearer { token } => {
+            let token = expose(token, "token")?;
+            headers.push((String::from("Authorization"), format!("Bearer {}", token)));
+        }
+        AuthConfig::Basic { username, password } => {
+            let password = expose(password, "password")?;
+            let credentials = STANDARD.encode(format!("{}:{}", username, password));
+            headers.push((
+                String::from("Authorization"),
+                format!("Basic {}", credentials),
+            ));
+        }
+        AuthConfig::ApiKey { header, query, key } => {
+            let key = expose(key, "key")?;
+            if let Some(header) = header {
+                headers.push((header.clone(), key.to_owned()));
+            }
+            if let Some(query) = query {
+                return with_query_param(uri, query, key);
+            }
+        }
+        AuthConfig::Hmac {
+            secret,
+            header,
+            prefix,
+            encoding,
+        } => {
+            let secret = expose(secret, "secret")?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC accepts keys of any length");
+            mac.update(body);
+            let signature = mac.finalize().into_bytes();
+            let encoded = match encoding {
+                SignatureEncoding::Hex => hex(&signature),
+                SignatureEncoding::Base64 => STANDARD.encode(signature),
+            };
+            headers.push((header.clone(), format!("{}{}", prefix, encoded)));
+        }
+        // Tokens are fetched ahead of time, see oauth2::TokenCache.
+        AuthConfig::OAuth2(_) => {}
+        AuthConfig::AwsSigV4(config) => {
+            sigv4::sign(config, method, &uri, headers, body, SystemTime::now())?;
+        }
+    }
+    Ok(uri)
+}
+
+fn with_query_param(uri: Uri, name: &str, value: &str) -> Result<Uri, AuthError> {
+    let mut parts = uri.into_parts();
+    let path_and_query = parts
+        .path_and_query
+        .as_ref()
+        .map(PathAndQuery::as_str)
+        .unwrap_or("/");
+    let separator = if path_and_query.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let path_and_query = format!(
+        "{}{}{}={}",
+        path_and_query,
+        separator,
+        utf8_percent_encode(name, COMPONENT),
+        utf8_percent_encode(value, COMPONENT)
+    );
+    parts.path_and_query = Some(
+        PathAndQuery::try_from(path_and_query)
+            .map_err(|err| AuthError::InvalidUri(err.to_string()))?,
+    );
+    Uri::from_parts(parts).map_err(|err| AuthError::InvalidUri(err.to_string()))
+}
+
+#[test]
+fn authenticate_requests() {
+    let uri = Uri::from_static("http://localhost:8080/lookup?v=2");
+    let secret = |value: &str| Secret::from(value.to_owned());
+
+    let mut headers = Vec::new();
+    let basic = AuthConfig::Basic {
+        username: String::from("Aladdin"),
+        password: secret("open sesame"),
+    };
+    authenticate(&basic, &Method::POST, uri.clone(), &mut headers, b"").unwrap();
+    assert_eq!(
+        headers,
+        vec![(
+            String::from("Authorization"),
+            String::from("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")
+        )]
+    );
+
+    let api_key = AuthConfig::ApiKey {
+        header: None,
+        query: Some(String::from("api_key")),
+        key: secret("a&b"),
+    };
+    let signed = authenticate(&api_key, &Method::POST, uri.clone(), &mut Vec::new(), b"").unwrap();
+    assert_eq!(signed, "http://localhost:8080/lookup?v=2&api_key=a%26b");
+
+    // RFC 4231, test case 2
+    let mut headers = Vec::new();
+    let hmac = AuthConfig::Hmac {
+        secret: secret("Jefe"),
+        header: String::from("X-Signature"),
+        prefix: String::from("sha256="),
+        encoding: SignatureEncoding::Hex,
+    };
+    authenticate(
+        &hmac,
+        &Method::POST,
+        uri,
+        &mut headers,
+        b"what do ya want for nothing?",
+    )
+    .unwrap();
+    assert_eq!(
+        headers,
+        vec![(
+            String::from("X-Signature"),
+            String::from("sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        )]
+    );
+}
@@ -0,0 +1,286 @@
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use actix_web::http::StatusCode;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hashbrown::HashMap;
+use percent_encoding::utf8_percent_encode;
+use serde_json::Value;
+
+use super::AuthError;
+use crate::{
+    config::{auth::OAuth2Config, request_mapping::COMPONENT},
+    routes::evaluate::{EvaluateError, JsonClient},
+};
+
+/* Tokens are refreshed this long before they expire, or halfway through shorter lifetimes. */
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+struct Token {
+    access_token: String,
+    refresh_at: Option<Instant>,
+}
+
+impl Token {
+    fn fresh(&self) -> bool {
+        match self.refresh_at {
+            Some(refresh_at) => Instant::now() < refresh_at,
+            None => true,
+        }
+    }
+}
+
+type Slot = Arc<tokio::sync::Mutex<Option<Token>>>;
+
+/* TokenCache
+ *
+ * OAuth2 access tokens, per service. A service's slot stays locked while its token is fetched,
+ * so concurrent requests wait for the one refresh instead of each making their own.
+ */
+#[derive(Default)]
+pub struct TokenCache {
+    slots: Mutex<HashMap<String, Slot>>,
+}
+
+impl TokenCache {
+    fn slots(&self) -> MutexGuard<'_, HashMap<String, Slot>> {
+        self.slots.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /* A cache for the next config, sharing the tokens of the services `keep` says still
+     * authenticate the same way.
+     */
+    pub fn carry_over(&self, keep: impl Fn(&str) -> bool) -> TokenCache {
+        let slots = self
+            .slots()
+            .iter()
+            .filter(|(service, _)| keep(service))
+            .map(|(service, slot)| (service.clone(), slot.clone()))
+            .collect();
+        TokenCache {
+            slots: Mutex::new(slots),
+        }
+    }
+
+    /* A token for `service`. `rejected` is a token the service refused, which is replaced unless
+     * another request has already done so. A token that has to be fetched gets `timeout`.
+     */
+    pub async fn token<JC: JsonClient>(
+        &self,
+        service: &str,
+        config: &OAuth2Config,
+        client: &JC,
+        rejected: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<String, EvaluateError> {
+        let slot = self.slots().entry(service.to_owned()).or_default().clone();
+        let mut slot = slot.lock().await;
+        if let Some(token) = slot.as_ref() {
+            if token.fresh() && Some(token.access_token.as_str()) != rejected {
+                return Ok(token.access_token.clone());
+            }
+        }
+        let token = fetch(config, client, timeout).await?;
+        let access_token = token.access_token.clone();
+        *slot = Some(token);
+        Ok(access_token)
+    }
+
+    /* Call `send` with an Authorization header, refreshing the token and retrying once if the
+     * service rejects it with a 401. `timeout` covers fetching tokens and sending alike, each is
+     * passed what's left of it.
+     */
+    pub async fn authorized<JC, F, Fut>(
+        &self,
+        service: &str,
+        config: &OAuth2Config,
+        client: &JC,
+        timeout: Option<Duration>,
+        send: F,
+    ) -> Result<Value, EvaluateError>
+    where
+        JC: JsonClient,
+        F: Fn((String, String), Option<Duration>) -> Fut,
+        Fut: Future<Output = Result<Value, EvaluateError>>,
+    {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let remaining =
+            || deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let bearer = |token: &str| (String::from("Authorization"), format!("Bearer {}", token));
+        let token = self
+            .token(service, config, client, None, remaining())
+            .await?;
+        match send(bearer(&token), remaining()).await {
+            Err(EvaluateError::NetworkError(StatusCode::UNAUTHORIZED, _)) => {
+                let token = self
+                    .token(service, config, client, Some(&token), remaining())
+                    .await?;
+                send(bearer(&token), remaining()).await
+            }
+            result => result,
+        }
+    }
+}
+
+async fn fetch<JC: JsonClient>(
+    config: &OAuth2Config,
+    client: &JC,
+    timeout: Option<Duration>,
+) -> Result<Token, EvaluateError> {
+    let unresolved = EvaluateError::Auth(AuthError::UnresolvedSecret("client-secret"));
+    let secret = config.client_secret.expose().ok_or(unresolved)?;
+    // RFC 6749 2.3.1: both are form-encoded before being joined.
+    let credentials = STANDARD.encode(format!(
+        "{}:{}",
+        utf8_percent_encode(&config.client_id, COMPONENT),
+        utf8_percent_encode(secret, COMPONENT)
+    ));
+    let mut form = vec![("grant_type", "client_credentials")];
+    if let Some(scope) = &config.scope {
+        form.push(("scope", scope));
+    }
+    if let Some(audience) = &config.audience {
+        form.push(("audience", audience));
+    }
+
+    let requested = Instant::now();
+    let response = client
+        .issue_form(
+            config.token_url.clone(),
+            &form,
+            vec![(
+                String::from("Authorization"),
+                format!("Basic {}", credentials),
+            )],
+            timeout,
+        )
+        .await
+        .map_err(|err| EvaluateError::Auth(AuthError::TokenRequest(Box::new(err))))?;
+    let invalid = |message: &str| EvaluateError::Auth(AuthError::InvalidToken(message.to_owned()));
+    let access_token = response
+        .get("access_token")
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid("missing access_token"))?;
+    let refresh_at = response
+        .get("expires_in")
+        .and_then(Value::as_u64)
+        .map(Duration::from_secs)
+        .map(|lifetime| requested + lifetime - EXPIRY_MARGIN.min(lifetime / 2));
+    Ok(Token {
+        access_token: access_token.to_owned(),
+        refresh_at,
+    })
+}
+
+#[actix_web::test]
+async fn oauth2_tokens() {
+    use actix_web::http::{Method, Uri};
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::config::auth::AuthConfig;
+
+    /* A token server handing out token-1, token-2, ..., in front of a service that has revoked
+     * token-1.
+     */
+    #[derive(Clone, Default)]
+    struct TokenServer {
+        issued: Arc<AtomicUsize>,
+        timeouts: Arc<Mutex<Vec<Option<Duration>>>>,
+    }
+
+    #[async_trait(?Send)]
+    impl JsonClient for TokenServer {
+        async fn issue_request(
+            &self,
+            _method: Method,
+            _uri: Uri,
+            _body: Option<&Value>,
+            headers: Vec<(String, String)>,
+            _auth: Option<&AuthConfig>,
+            _timeout: Option<Duration>,
+        ) -> Result<Value, EvaluateError> {
+            match headers.iter().find(|(name, _)| name == "Authorization") {
+                Some((_, value)) if value == "Bearer token-1" => Err(EvaluateError::NetworkError(
+                    StatusCode::UNAUTHORIZED,
+                    Value::Null,
+                )),
+                authorization => Ok(serde_json::json!(authorization.map(|(_, value)| value))),
+            }
+        }
+
+        async fn issue_form(
+            &self,
+            _uri: Uri,
+            form: &[(&str, &str)],
+            headers: Vec<(String, String)>,
+            timeout: Option<Duration>,
+        ) -> Result<Value, EvaluateError> {
+            assert_eq!(form, [("grant_type", "client_credentials")]);
+            self.timeouts.lock().unwrap().push(timeout);
+            assert_eq!(headers[0].1, "Basic ZGVsZWdhdG9yOnMlM0FjcmV0");
+            actix_web::rt::task::yield_now().await;
+            let issued = self.issued.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(serde_json::json!({"access_token": format!("token-{}", issued), "expires_in": 3600}))
+        }
+    }
+
+    let config: OAuth2Config = toml::from_str(
+        r#"
+        token-url = "http://localhost:8080/oauth/token"
+        client-id = "delegator"
+        client-secret = "s:cret"
+        "#,
+    )
+    .unwrap();
+    let cache = Arc::new(TokenCache::default());
+    let server = TokenServer::default();
+
+    let tasks: Vec<_> = (0..4)
+        .map(|_| {
+            let (cache, config, server) = (cache.clone(), config.clone(), server.clone());
+            actix_web::rt::spawn(async move {
+                cache
+                    .token("pricing", &config, &server, None, None)
+                    .await
+                    .unwrap()
+            })
+        })
+        .collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), "token-1");
+    }
+    assert_eq!(server.issued.load(Ordering::SeqCst), 1);
+
+    // The upstream rejects token-1, so it's refreshed and the call retried once. The refresh
+    // gets what's left of the call's timeout.
+    let timeout = Duration::from_secs(5);
+    let result = cache
+        .authorized(
+            "pricing",
+            &config,
+            &server,
+            Some(timeout),
+            |bearer, timeout| {
+                let uri = Uri::from_static("http://localhost:8081/quote");
+                server.issue_request(Method::POST, uri, None, vec![bearer], None, timeout)
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(result, "Bearer token-2");
+    assert_eq!(server.issued.load(Ordering::SeqCst), 2);
+    let refreshed = server.timeouts.lock().unwrap()[1];
+    assert!(refreshed.is_some_and(|refreshed| refreshed <= timeout));
+
+    // Tokens are only carried over for the services asked for.
+    let kept = cache.carry_over(|service| service == "pricing");
+    let token = kept.token("pricing", &config, &server, None, None);
+    assert_eq!(token.await.unwrap(), "token-2");
+    let dropped = cache.carry_over(|_| false);
+    let token = dropped.token("pricing", &config, &server, None, None);
+    assert_eq!(token.await.unwrap(), "token-3");
+}
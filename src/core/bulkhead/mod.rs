@@ -0,0 +1,142 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tokio::sync::{Semaphore, SemaphorePermit, TryAcquireError};
+
+use crate::{config::bulkhead::BulkheadConfig, routes::evaluate::EvaluateError};
+
+/* Why a call was turned away. */
+#[derive(Debug)]
+pub enum Rejection {
+    QueueFull(u32),
+    QueueTimeout(Duration),
+}
+
+/* Bulkhead
+ *
+ * Limits the calls in flight to one service, queueing a bounded number of the rest.
+ */
+pub struct Bulkhead {
+    service: String,
+    config: BulkheadConfig,
+    slots: Semaphore,
+    queued: AtomicU32,
+}
+
+/* Counts a call as queued for as long as it waits, even if the wait is abandoned. */
+struct Queued<'a>(&'a AtomicU32);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Bulkhead {
+    pub fn new(service: &str, config: &BulkheadConfig) -> Bulkhead {
+        Bulkhead {
+            service: service.to_owned(),
+            config: config.clone(),
+            slots: Semaphore::new(config.max_concurrent.get() as usize),
+            queued: AtomicU32::new(0),
+        }
+    }
+
+    /* Whether this bulkhead was built from `config`, so it can outlive a reload. */
+    pub fn configured_as(&self, config: &BulkheadConfig) -> bool {
+        self.config == *config
+    }
+
+    /* A slot for one call, held until it's dropped. A call with a `timeout` doesn't wait past it
+     * for a slot, even if the queue timeout is longer.
+     */
+    pub async fn enter(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<SemaphorePermit<'_>, EvaluateError> {
+        let rejected = |rejection| EvaluateError::ServiceBusy(self.service.clone(), rejection);
+        match self.slots.try_acquire() {
+            Ok(permit) => return Ok(permit),
+            Err(TryAcquireError::Closed) => unreachable!("the semaphore is never closed"),
+            Err(TryAcquireError::NoPermits) => {}
+        }
+        let max_queued = self.config.max_queued;
+        let queued = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < max_queued).then_some(queued + 1)
+            });
+        if queued.is_err() {
+            return Err(rejected(Rejection::QueueFull(max_queued)));
+        }
+        let _queued = Queued(&self.queued);
+        let wait = timeout.map_or(self.config.queue_timeout, |timeout| {
+            timeout.min(self.config.queue_timeout)
+        });
+        match actix_web::rt::time::timeout(wait, self.slots.acquire()).await {
+            Ok(permit) => Ok(permit.expect("the semaphore is never closed")),
+            Err(_) => Err(rejected(Rejection::QueueTimeout(wait))),
+        }
+    }
+
+    /* Calls in flight and waiting, for the introspection endpoint. */
+    pub fn status(&self) -> Value {
+        let max_concurrent = self.config.max_concurrent.get();
+        json!({
+            "in_flight": max_concurrent as usize - self.slots.available_permits(),
+            "max_concurrent": max_concurrent,
+            "queued": self.queued.load(Ordering::SeqCst),
+            "max_queued": self.config.max_queued,
+        })
+    }
+}
+
+#[actix_web::test]
+async fn limit_concurrent_calls() {
+    let config: BulkheadConfig = toml::from_str(
+        r#"
+        max-concurrent = 1
+        max-queued = 1
+        queue-timeout = "20ms"
+        "#,
+    )
+    .unwrap();
+    let bulkhead = std::sync::Arc::new(Bulkhead::new("pricing", &config));
+
+    // One call in flight and one waiting; a third is turned away straight away.
+    let first = bulkhead.enter(None).await.unwrap();
+    let second = {
+        let bulkhead = bulkhead.clone();
+        actix_web::rt::spawn(async move { bulkhead.enter(None).await.is_ok() })
+    };
+    while bulkhead.status()["queued"] != 1 {
+        actix_web::rt::task::yield_now().await;
+    }
+    let third = bulkhead.enter(None).await;
+    assert!(matches!(
+        third,
+        Err(EvaluateError::ServiceBusy(_, Rejection::QueueFull(1)))
+    ));
+    assert_eq!(bulkhead.status()["in_flight"], 1);
+    drop(first);
+    assert!(second.await.unwrap());
+
+    // A queued call gives up after the queue timeout.
+    let _held = bulkhead.enter(None).await.unwrap();
+    let timed_out = bulkhead.enter(None).await;
+    assert!(matches!(
+        timed_out,
+        Err(EvaluateError::ServiceBusy(_, Rejection::QueueTimeout(_)))
+    ));
+    assert_eq!(bulkhead.status()["queued"], 0);
+
+    // A call doesn't wait for a slot for longer than it has left.
+    let timed_out = bulkhead.enter(Some(Duration::from_millis(5))).await;
+    assert!(matches!(
+        timed_out,
+        Err(EvaluateError::ServiceBusy(_, Rejection::QueueTimeout(wait))) if wait == Duration::from_millis(5)
+    ));
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Instant,
+};
+
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use crate::config::circuit_breaker::CircuitBreakerConfig;
+
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { trials: u32, succeeded: u32 },
+}
+
+struct Circuit {
+    state: State,
+    /* Whether each of the latest calls failed, oldest first. */
+    outcomes: VecDeque<bool>,
+}
+
+/* CircuitBreaker
+ *
+ * Tracks the outcome of calls to one service, and turns calls away while it's failing.
+ */
+pub struct CircuitBreaker {
+    service: String,
+    config: CircuitBreakerConfig,
+    circuit: Mutex<Circuit>,
+}
+
+/* Permission to make one call. A trial call in the half-open state that's dropped without an
+ * outcome, because the request was abandoned, frees its place for another.
+ */
+pub struct Permit<'a> {
+    breaker: &'a CircuitBreaker,
+    trial: bool,
+    recorded: bool,
+}
+
+impl Permit<'_> {
+    pub fn record(mut self, failed: bool) {
+        self.recorded = true;
+        self.breaker.record(self.trial, failed);
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.trial && !self.recorded {
+            if let State::HalfOpen { trials, .. } = &mut self.breaker.circuit().state {
+                *trials = trials.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(service: &str, config: &CircuitBreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            service: service.to_owned(),
+            config: config.clone(),
+            circuit: Mutex::new(Circuit {
+                state: State::Closed,
+                outcomes: VecDeque::new(),
+            }),
+        }
+    }
+
+    /* Whether this breaker was built from `config`, so its state can outlive a reload. */
+    pub fn configured_as(&self, config: &CircuitBreakerConfig) -> bool {
+        self.config == *config
+    }
+
+    fn circuit(&self) -> MutexGuard<'_, Circuit> {
+        self.circuit.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /* A permit for a call, or None if the circuit is turning calls away. */
+    pub fn permit(&self) -> Option<Permit<'_>> {
+        let mut circuit = self.circuit();
+        if let State::Open { until } = circuit.state {
+            if Instant::now() < until {
+                return None;
+            }
+            circuit.state = State::HalfOpen {
+                trials: 0,
+                succeeded: 0,
+            };
+        }
+        let trial = match &mut circuit.state {
+            State::Closed => false,
+            State::HalfOpen { trials, succeeded } => {
+                if *trials + *succeeded >= self.config.half_open_calls.get() {
+                    return None;
+                }
+                *trials += 1;
+                true
+            }
+            State::Open { .. } => unreachable!("open circuits were handled above"),
+        };
+        Some(Permit {
+            breaker: self,
+            trial,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, trial: bool, failed: bool) {
+        let mut circuit = self.circuit();
+        match (&mut circuit.state, trial) {
+            (State::Closed, false) => {
+                circuit.outcomes.push_back(failed);
+                if circuit.outcomes.len() > self.config.window.get() as usize {
+                    circuit.outcomes.pop_front();
+                }
+                let calls = circuit.outcomes.len();
+                let failures = circuit.outcomes.iter().filter(|failed| **failed).count();
+                if calls >= self.config.minimum_calls.get() as usize
+                    && failures as f64 >= self.config.failure_rate * calls as f64
+                {
+                    warn!(
+                        "Opening the circuit to {} for {:?}, after {} of the last {} calls failed",
+                        self.service, self.config.open_time, failures, calls
+                    );
+                    self.open(&mut circuit);
+                }
+            }
+            (State::HalfOpen { .. }, true) if failed => {
+                warn!(
+                    "Opening the circuit to {} again for {:?}, after a trial call failed",
+                    self.service, self.config.open_time
+                );
+                self.open(&mut circuit);
+            }
+            (State::HalfOpen { trials, succeeded }, true) => {
+                *trials = trials.saturating_sub(1);
+                *succeeded += 1;
+                if *succeeded >= self.config.half_open_calls.get() {
+                    info!("Closing the circuit to {}", self.service);
+                    circuit.state = State::Closed;
+                }
+            }
+            // Calls that started before the circuit last changed state say nothing about it now.
+            _ => {}
+        }
+    }
+
+    fn open(&self, circuit: &mut Circuit) {
+        circuit.state = State::Open {
+            until: Instant::now() + self.config.open_time,
+        };
+        circuit.outcomes.clear();
+    }
+
+    /* The circuit's state, for the introspection endpoint. */
+    pub fn status(&self) -> Value {
+        let circuit = self.circuit();
+        let calls = circuit.outcomes.len();
+        let failures = circuit.outcomes.iter().filter(|failed| **failed).count();
+        let mut status = json!({"calls": calls, "failures": failures});
+        match circuit.state {
+            State::Closed => status["state"] = json!("closed"),
+            State::Open { until } => {
+                let remaining = until.saturating_duration_since(Instant::now());
+                status["state"] = json!("open");
+                status["open_ms"] = json!(remaining.as_millis() as u64);
+            }
+            State::HalfOpen { trials, succeeded } => {
+                status["state"] = json!("half-open");
+                status["trials"] = json!(trials);
+                status["succeeded"] = json!(succeeded);
+            }
+        }
+        status
+    }
+}
+
+#[test]
+fn trip_circuit_breakers() {
+    use std::time::Duration;
+
+    let config: CircuitBreakerConfig = toml::from_str(
+        r#"
+        failure-rate = 0.5
+        window = 4
+        minimum-calls = 4
+        open-time = "20ms"
+        half-open-calls = 2
+        "#,
+    )
+    .unwrap();
+    let breaker = CircuitBreaker::new("pricing", &config);
+    let call = |failed: bool| breaker.permit().map(|permit| permit.record(failed));
+
+    // Half of the last four calls failing opens the circuit, but not before there are four.
+    for failed in [true, true, false] {
+        assert!(call(failed).is_some());
+    }
+    assert_eq!(breaker.status()["state"], "closed");
+    assert!(call(false).is_some());
+    assert_eq!(breaker.status()["state"], "open");
+    assert!(breaker.permit().is_none());
+
+    // Once it's been open a while, only `half-open-calls` are let through.
+    std::thread::sleep(Duration::from_millis(30));
+    let trials = [breaker.permit().unwrap(), breaker.permit().unwrap()];
+    assert!(breaker.permit().is_none());
+    assert_eq!(breaker.status()["state"], "half-open");
+    let [first, second] = trials;
+    first.record(false);
+    second.record(true);
+    assert_eq!(breaker.status()["state"], "open");
+
+    // A trial call that's abandoned frees its place, and the trials succeeding close it.
+    std::thread::sleep(Duration::from_millis(30));
+    drop(breaker.permit().unwrap());
+    assert!(call(false).is_some());
+    assert!(call(false).is_some());
+    assert_eq!(breaker.status()["state"], "closed");
+}
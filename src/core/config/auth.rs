@@ -0,0 +1,160 @@
+use actix_web::http::Uri;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::secret::{self, Secret};
+
+fn default_signature_header() -> String {
+    String::from("X-Signature")
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+/* AuthConfig
+ *
+ * How requests to a service are authenticated, applied to every call to it:
+ *
+ *   auth = { type = "bearer", token = { env = "CATALOG_TOKEN" } }
+ *   auth = { type = "basic", username = "delegator", password = { file = "/run/secrets/pw" } }
+ *   auth = { type = "api-key", header = "X-Api-Key", key = { env = "PRICING_KEY" } }
+ *   auth = { type = "api-key", query = "api_key", key = { env = "PRICING_KEY" } }
+ *   auth = { type = "hmac", secret = { env = "SIGNING_KEY" }, header = "X-Hub-Signature-256",
+ *            prefix = "sha256=" }
+ *   auth = { type = "oauth2", token-url = "https://auth.internal/oauth/token",
+ *            client-id = "delegator", client-secret = { env = "CLIENT_SECRET" } }
+ *   auth = { type = "aws-sigv4", region = "us-east-1", service = "execute-api" }
+ *
+ * HMAC signs the exact bytes of the request body with SHA-256, hex-encoded by default.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum AuthConfig {
+    Bearer {
+        token: Secret,
+    },
+    Basic {
+        username: String,
+        password: Secret,
+    },
+    ApiKey {
+        header: Option<String>,
+        query: Option<String>,
+        key: Secret,
+    },
+    Hmac {
+        secret: Secret,
+        #[serde(default = "default_signature_header")]
+        header: String,
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        encoding: SignatureEncoding,
+    },
+    #[serde(rename = "oauth2")]
+    OAuth2(OAuth2Config),
+    #[serde(rename = "aws-sigv4")]
+    AwsSigV4(SigV4Config),
+}
+
+/* OAuth2Config
+ *
+ * The client credentials grant: tokens are requested from `token-url`, authenticating with
+ * `client-id` and `client-secret` over HTTP Basic, and sent as bearer tokens.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub struct OAuth2Config {
+    #[serde(rename = "token-url", alias = "token_url", with = "http_serde::uri")]
+    #[schemars(with = "String")]
+    pub token_url: Uri,
+    #[serde(rename = "client-id", alias = "client_id")]
+    pub client_id: String,
+    #[serde(rename = "client-secret", alias = "client_secret")]
+    pub client_secret: Secret,
+    pub scope: Option<String>,
+    pub audience: Option<String>,
+}
+
+fn default_access_key_id() -> Secret {
+    Secret::env("AWS_ACCESS_KEY_ID")
+}
+
+fn default_secret_access_key() -> Secret {
+    Secret::env("AWS_SECRET_ACCESS_KEY")
+}
+
+/* SigV4Config
+ *
+ * AWS Signature Version 4, for API gateways and other IAM-authenticated services other than S3.
+ * Credentials default to the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment variables.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct SigV4Config {
+    pub region: String,
+    pub service: String,
+    #[serde(
+        rename = "access-key-id",
+        alias = "access_key_id",
+        default = "default_access_key_id"
+    )]
+    pub access_key_id: Secret,
+    #[serde(
+        rename = "secret-access-key",
+        alias = "secret_access_key",
+        default = "default_secret_access_key"
+    )]
+    pub secret_access_key: Secret,
+    #[serde(rename = "session-token", alias = "session_token")]
+    pub session_token: Option<Secret>,
+}
+
+impl AuthConfig {
+    /* Every secret, with the name of the field holding it. */
+    pub fn secrets_mut(&mut self) -> Vec<(&'static str, &mut Secret)> {
+        match self {
+            AuthConfig::Bearer { token } => vec![("token", token)],
+            AuthConfig::Basic { password, .. } => vec![("password", password)],
+            AuthConfig::ApiKey { key, .. } => vec![("key", key)],
+            AuthConfig::Hmac { secret, .. } => vec![("secret", secret)],
+            AuthConfig::OAuth2(config) => vec![("client-secret", &mut config.client_secret)],
+            AuthConfig::AwsSigV4(config) => {
+                let mut secrets = vec![
+                    ("access-key-id", &mut config.access_key_id),
+                    ("secret-access-key", &mut config.secret_access_key),
+                ];
+                if let Some(session_token) = &mut config.session_token {
+                    secrets.push(("session-token", session_token));
+                }
+                secrets
+            }
+        }
+    }
+}
+
+/* Fields that may hold a credential inline, blanked out when printing the configuration. */
+const SECRET_FIELDS: &[&str] = &[
+    "token",
+    "password",
+    "key",
+    "secret",
+    "client-secret",
+    "client_secret",
+    "secret-access-key",
+    "secret_access_key",
+    "session-token",
+    "session_token",
+];
+
+pub fn redact(auth: &mut Value) {
+    for field in SECRET_FIELDS {
+        if let Some(value @ Value::String(_)) = auth.get_mut(*field) {
+            *value = Value::from(secret::REDACTED);
+        }
+    }
+}
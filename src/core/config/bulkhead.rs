@@ -0,0 +1,40 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::stringy_duration;
+
+fn default_max_queued() -> u32 {
+    100
+}
+
+fn default_queue_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+/* BulkheadConfig
+ *
+ * A limit on calls to one service, so that a slow one can't tie up every worker. At most
+ * `max-concurrent` calls are made at once; up to `max-queued` more wait for a turn, for as long
+ * as `queue-timeout`, and any beyond that are turned away.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub struct BulkheadConfig {
+    #[serde(rename = "max-concurrent", alias = "max_concurrent")]
+    pub max_concurrent: NonZeroU32,
+    #[serde(
+        rename = "max-queued",
+        alias = "max_queued",
+        default = "default_max_queued"
+    )]
+    pub max_queued: u32,
+    #[serde(
+        rename = "queue-timeout",
+        alias = "queue_timeout",
+        default = "default_queue_timeout",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub queue_timeout: Duration,
+}
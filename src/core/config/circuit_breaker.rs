@@ -0,0 +1,65 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::stringy_duration;
+
+fn default_failure_rate() -> f64 {
+    0.5
+}
+
+fn default_window() -> NonZeroU32 {
+    NonZeroU32::new(20).expect("nonzero")
+}
+
+fn default_minimum_calls() -> NonZeroU32 {
+    NonZeroU32::new(10).expect("nonzero")
+}
+
+fn default_open_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_half_open_calls() -> NonZeroU32 {
+    NonZeroU32::new(3).expect("nonzero")
+}
+
+/* CircuitBreakerConfig
+ *
+ * When to stop calling a service that's failing. The circuit opens once at least
+ * `failure-rate` of the last `window` calls have failed, counting from `minimum-calls`, and
+ * calls fail straight away while it's open. After `open-time` it lets `half-open-calls` calls
+ * through: if they all succeed it closes again, and if any fails it opens again.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub struct CircuitBreakerConfig {
+    #[serde(
+        rename = "failure-rate",
+        alias = "failure_rate",
+        default = "default_failure_rate"
+    )]
+    pub failure_rate: f64,
+    #[serde(default = "default_window")]
+    pub window: NonZeroU32,
+    #[serde(
+        rename = "minimum-calls",
+        alias = "minimum_calls",
+        default = "default_minimum_calls"
+    )]
+    pub minimum_calls: NonZeroU32,
+    #[serde(
+        rename = "open-time",
+        alias = "open_time",
+        default = "default_open_time",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub open_time: Duration,
+    #[serde(
+        rename = "half-open-calls",
+        alias = "half_open_calls",
+        default = "default_half_open_calls"
+    )]
+    pub half_open_calls: NonZeroU32,
+}
@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{Schema, SchemaObject, SubschemaValidation},
+    JsonSchema,
+};
+use serde::{
+    de::{
+        value::{MapAccessDeserializer, SeqAccessDeserializer},
+        MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer,
+};
+
+use super::stringy_duration;
+
+/* CorsConfig
+ *
+ * Either a list of allowed origins (eg: `cors = ["localhost:3000", "*.example.com"]`), or a table:
+ *
+ *   [http.cors]
+ *   origins = ["https://app.example.com", "http://localhost:3000"]
+ *   methods = ["GET", "POST"]         # default: any method
+ *   headers = ["content-type"]        # default: any header
+ *   credentials = true                # default: false
+ *   max-age = "1h"                    # default: 1h
+ *
+ * Origin patterns may omit the scheme to match any scheme, use `*.` to match any subdomain, and
+ * `*` on its own matches every origin. `*` can't be combined with `credentials`, browsers would
+ * send cookies to us from any site.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct CorsConfig {
+    pub origins: Vec<String>,
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<String>>,
+    pub credentials: bool,
+    pub max_age: Duration,
+}
+
+fn default_max_age() -> Duration {
+    Duration::from_secs(3600)
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origins: Vec::new(),
+            methods: None,
+            headers: None,
+            credentials: false,
+            max_age: default_max_age(),
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct CorsTable {
+    #[serde(default)]
+    origins: Vec<String>,
+    methods: Option<Vec<String>>,
+    headers: Option<Vec<String>>,
+    #[serde(default)]
+    credentials: bool,
+    #[serde(
+        rename = "max-age",
+        alias = "max_age",
+        default = "default_max_age",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    max_age: Duration,
+}
+
+struct CorsConfigVisitor;
+
+impl<'de> Visitor<'de> for CorsConfigVisitor {
+    type Value = CorsConfig;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("EXPECTED: a list of origins, or a table with `origins`")
+    }
+
+    fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let origins = Vec::<String>::deserialize(SeqAccessDeserializer::new(seq))?;
+        Ok(CorsConfig {
+            origins,
+            ..CorsConfig::default()
+        })
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let table = CorsTable::deserialize(MapAccessDeserializer::new(map))?;
+        Ok(CorsConfig {
+            origins: table.origins,
+            methods: table.methods,
+            headers: table.headers,
+            credentials: table.credentials,
+            max_age: table.max_age,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for CorsConfig {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        de.deserialize_any(CorsConfigVisitor)
+    }
+}
+
+impl JsonSchema for CorsConfig {
+    fn schema_name() -> String {
+        String::from("CorsConfig")
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                any_of: Some(vec![
+                    gen.subschema_for::<Vec<String>>(),
+                    gen.subschema_for::<CorsTable>(),
+                ]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+fn split_origin(origin: &str) -> (Option<&str>, &str) {
+    match origin.split_once("://") {
+        Some((scheme, rest)) => (Some(scheme), rest),
+        None => (None, origin),
+    }
+}
+
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" {
+        return true;
+    }
+    let (pattern_scheme, pattern_authority) = split_origin(pattern);
+    let (origin_scheme, origin_authority) = split_origin(origin);
+    if pattern_scheme.is_some() && pattern_scheme != origin_scheme {
+        return false;
+    }
+
+    let pattern_authority = pattern_authority.to_ascii_lowercase();
+    let origin_authority = origin_authority.to_ascii_lowercase();
+    match pattern_authority.strip_prefix("*.") {
+        Some(suffix) => origin_authority
+            .strip_suffix(suffix)
+            .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+        None => pattern_authority == origin_authority,
+    }
+}
+
+impl CorsConfig {
+    /* Whether every origin is allowed, which is answered with a literal `*`. */
+    pub fn allows_any_origin(&self) -> bool {
+        self.origins.iter().any(|pattern| pattern == "*")
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|pattern| origin_matches(pattern, origin))
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        match &self.methods {
+            Some(methods) => methods.iter().any(|m| m.eq_ignore_ascii_case(method)),
+            None => true,
+        }
+    }
+
+    pub fn allows_header(&self, header: &str) -> bool {
+        match &self.headers {
+            Some(headers) => headers.iter().any(|h| h.eq_ignore_ascii_case(header)),
+            None => true,
+        }
+    }
+}
+
+#[test]
+fn cors_origin_patterns() {
+    let cors: CorsConfig = serde_json::from_value(serde_json::json!([
+        "localhost:3000",
+        "https://*.example.com",
+        "https://example.org"
+    ]))
+    .unwrap();
+
+    assert!(cors.allows_origin("http://localhost:3000"));
+    assert!(cors.allows_origin("https://localhost:3000"));
+    assert!(!cors.allows_origin("http://localhost:3001"));
+    assert!(cors.allows_origin("https://app.example.com"));
+    assert!(cors.allows_origin("https://a.b.Example.com"));
+    assert!(!cors.allows_origin("https://example.com"));
+    assert!(!cors.allows_origin("https://evilexample.com"));
+    assert!(!cors.allows_origin("http://app.example.com"));
+    assert!(cors.allows_origin("https://example.org"));
+    assert!(!cors.allows_origin("https://example.org.evil.com"));
+
+    let cors: CorsConfig = serde_json::from_value(serde_json::json!({
+        "origins": ["*"],
+        "methods": ["GET", "POST"],
+        "max-age": "60s",
+    }))
+    .unwrap();
+    assert!(cors.allows_origin("https://anything.test"));
+    assert!(cors.allows_any_origin());
+    assert!(cors.allows_method("post"));
+    assert!(!cors.allows_method("DELETE"));
+    assert!(cors.allows_header("x-anything"));
+    assert_eq!(cors.max_age, Duration::from_secs(60));
+}
@@ -0,0 +1,158 @@
+use std::{fmt, path::PathBuf};
+
+use serde_path_to_error::{Path, Segment as PathSegment};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/* KeyPath
+ *
+ * The location of a value within the configuration document, rendered the way it would be
+ * written in a TOML key, eg: virtualhosts.catalog.routes."/explore".cryptogram
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyPath(Vec<Segment>);
+
+impl KeyPath {
+    pub fn root() -> KeyPath {
+        KeyPath(Vec::new())
+    }
+
+    pub fn key(&self, key: &str) -> KeyPath {
+        let mut segments = self.0.clone();
+        segments.push(Segment::Key(key.to_owned()));
+        KeyPath(segments)
+    }
+
+    pub fn index(&self, index: usize) -> KeyPath {
+        let mut segments = self.0.clone();
+        segments.push(Segment::Index(index));
+        KeyPath(segments)
+    }
+
+    pub fn join(&self, path: &Path) -> KeyPath {
+        let mut segments = self.0.clone();
+        for segment in path.iter() {
+            match segment {
+                PathSegment::Seq { index } => segments.push(Segment::Index(*index)),
+                PathSegment::Map { key } => segments.push(Segment::Key(key.clone())),
+                PathSegment::Enum { .. } | PathSegment::Unknown => {}
+            }
+        }
+        KeyPath(segments)
+    }
+
+    pub fn concat(&self, suffix: &KeyPath) -> KeyPath {
+        let mut segments = self.0.clone();
+        segments.extend(suffix.0.iter().cloned());
+        KeyPath(segments)
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn starts_with(&self, prefix: &KeyPath) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
+}
+
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let is_bare = |key: &str| {
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        };
+        for (idx, segment) in self.0.iter().enumerate() {
+            match segment {
+                Segment::Key(key) => {
+                    if idx > 0 {
+                        f.write_str(".")?;
+                    }
+                    if is_bare(key) {
+                        f.write_str(key)?;
+                    } else {
+                        write!(f, "{:?}", key)?;
+                    }
+                }
+                Segment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn from_offset(text: &str, offset: usize) -> Location {
+        let before = &text[..offset.min(text.len())];
+        let line_start = before.rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+        Location {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: String,
+    pub location: Option<Location>,
+    pub key_path: Option<KeyPath>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let Some(Location { line, column }) = self.location {
+            write!(f, ":{}:{}", line, column)?;
+        }
+        match &self.key_path {
+            Some(key_path) if !key_path.is_root() => write!(f, ": {}", key_path)?,
+            _ => {}
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/* ConfigErrors
+ *
+ * Everything wrong with a configuration, along with every file that was read while loading it,
+ * so that a failed reload still notices when one of them is fixed.
+ */
+#[derive(Debug)]
+pub struct ConfigErrors {
+    pub errors: Vec<ConfigError>,
+    pub sources: Vec<PathBuf>,
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, error) in self.errors.iter().enumerate() {
+            if idx > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
@@ -1,11 +1,12 @@
+use schemars::JsonSchema;
 use serde::Deserialize;
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, JsonSchema)]
 pub struct EventConfig {
     pub user_action: EventTopic,
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
 pub struct EventTopic {
     pub queue_url: String,
 }
@@ -0,0 +1,129 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use actix_web::http::{uri::PathAndQuery, Method};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use super::{http_method, path_and_query, stringy_duration};
+
+fn default_method() -> Method {
+    Method::GET
+}
+
+fn default_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_probe_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_healthy_threshold() -> NonZeroU32 {
+    NonZeroU32::new(2).expect("nonzero")
+}
+
+fn default_unhealthy_threshold() -> NonZeroU32 {
+    NonZeroU32::new(3).expect("nonzero")
+}
+
+fn default_consecutive_failures() -> NonZeroU32 {
+    NonZeroU32::new(5).expect("nonzero")
+}
+
+fn default_ejection_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_max_ejection_time() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_slow_start() -> Duration {
+    Duration::from_secs(30)
+}
+
+/* ProbeConfig
+ *
+ * An active health check: every `interval`, each authority is sent a request for `path`. It's
+ * taken out of rotation after `unhealthy-threshold` failed probes in a row, and returns after
+ * `healthy-threshold` successful ones. Any 2xx response is a success.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub struct ProbeConfig {
+    #[serde(deserialize_with = "path_and_query::deserialize")]
+    #[schemars(schema_with = "path_and_query::schema")]
+    pub path: PathAndQuery,
+    #[serde(
+        default = "default_method",
+        deserialize_with = "http_method::deserialize"
+    )]
+    #[schemars(schema_with = "http_method::schema")]
+    pub method: Method,
+    #[serde(
+        default = "default_interval",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub interval: Duration,
+    #[serde(
+        default = "default_probe_timeout",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub timeout: Duration,
+    #[serde(
+        rename = "healthy-threshold",
+        alias = "healthy_threshold",
+        default = "default_healthy_threshold"
+    )]
+    pub healthy_threshold: NonZeroU32,
+    #[serde(
+        rename = "unhealthy-threshold",
+        alias = "unhealthy_threshold",
+        default = "default_unhealthy_threshold"
+    )]
+    pub unhealthy_threshold: NonZeroU32,
+}
+
+/* HealthConfig
+ *
+ * How a service's authorities are taken out of rotation and brought back. Calls that fail with a
+ * connection error, a timeout or a 5xx count against the authority that served them; after
+ * `consecutive-failures` in a row it's ejected for `ejection-time`, growing with each ejection
+ * up to `max-ejection-time`. An authority coming back, from ejection or a failing `probe`, takes
+ * a growing share of its calls over `slow-start`.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub struct HealthConfig {
+    pub probe: Option<ProbeConfig>,
+    #[serde(
+        rename = "consecutive-failures",
+        alias = "consecutive_failures",
+        default = "default_consecutive_failures"
+    )]
+    pub consecutive_failures: NonZeroU32,
+    #[serde(
+        rename = "ejection-time",
+        alias = "ejection_time",
+        default = "default_ejection_time",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub ejection_time: Duration,
+    #[serde(
+        rename = "max-ejection-time",
+        alias = "max_ejection_time",
+        default = "default_max_ejection_time",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub max_ejection_time: Duration,
+    #[serde(
+        rename = "slow-start",
+        alias = "slow_start",
+        default = "default_slow_start",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub slow_start: Duration,
+}
@@ -1,6 +1,10 @@
 use std::str::FromStr;
 
 use actix_web::http::Method;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+};
 use serde::{de::Visitor, Deserializer};
 
 struct ConfigHttpMethodVisitor;
@@ -26,3 +30,13 @@ where
 {
     de.deserialize_str(ConfigHttpMethodVisitor)
 }
+
+pub fn schema(_gen: &mut SchemaGenerator) -> Schema {
+    let methods = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(methods.iter().map(|&method| method.into()).collect()),
+        ..Default::default()
+    }
+    .into()
+}
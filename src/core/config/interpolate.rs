@@ -0,0 +1,507 @@
+use std::{fmt, ops::Range};
+
+use super::source::Format;
+
+/* Environment variable interpolation
+ *
+ * Config files are expanded before they are parsed, so placeholders work anywhere in the
+ * document: quoted strings, bare values (eg: `port = ${HTTP_PORT:-8080}`), and inside
+ * embedded cryptograms. Comments are left alone.
+ *
+ *   ${VAR}          -- the value of VAR, which must be set
+ *   ${VAR:-default} -- the value of VAR, or `default` if VAR is unset or empty
+ *   $${             -- a literal `${`
+ *
+ * A variable's value is escaped for the kind of string it lands in, so that it can't end the
+ * string early or add keys to the document. Where that isn't possible, eg: a quote in a TOML
+ * literal string, or anything but a plain word outside a string, it's an error. Defaults are
+ * part of the file, and are written as they are.
+ *
+ * A cryptogram embedded as a string is JSON within that string, so a value landing inside it is
+ * escaped for the JSON first, and then for the string holding it.
+ */
+
+/* Errors carry the byte offset of the offending placeholder within the input. */
+#[derive(Debug, PartialEq)]
+pub enum InterpolationError {
+    MissingVariable { name: String, offset: usize },
+    Unterminated { offset: usize },
+    Unescapable { name: String, offset: usize },
+}
+
+impl fmt::Display for InterpolationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpolationError::MissingVariable { name, .. } => write!(
+                f,
+                "environment variable {} is not set (use ${{{}:-default}} to make it optional)",
+                name, name
+            ),
+            InterpolationError::Unterminated { .. } => {
+                f.write_str("unterminated ${...} placeholder")
+            }
+            InterpolationError::Unescapable { name, .. } => write!(
+                f,
+                "the value of environment variable {} can't be written here as it is, put the placeholder in a double-quoted string",
+                name
+            ),
+        }
+    }
+}
+
+/* Where a placeholder is in the document, which decides how its value is written. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Context {
+    /* Outside any string, where only a plain word is safe. */
+    Bare,
+    Comment,
+    /* TOML basic strings, YAML double-quoted scalars and JSON strings, which take backslash
+     * escapes. `triple` strings end with `"""`.
+     */
+    Escaped { triple: bool },
+    /* TOML literal strings, which can't escape anything. */
+    Literal { triple: bool },
+    /* YAML single-quoted scalars, where a quote is written twice. */
+    SingleQuoted,
+    /* YAML block scalars, the lines after a `|` or `>` indented deeper than `indent`. */
+    Block { indent: usize },
+}
+
+fn escape(context: Context, value: &str) -> Option<String> {
+    let single_line = !value.contains(['\n', '\r']);
+    match context {
+        Context::Bare => value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-+/:@%~".contains(c))
+            .then(|| value.to_owned()),
+        Context::Escaped { .. } => {
+            let quoted = serde_json::to_string(value).expect("strings always serialize");
+            Some(quoted[1..quoted.len() - 1].to_owned())
+        }
+        Context::Literal { triple: false } => {
+            (single_line && !value.contains('\'')).then(|| value.to_owned())
+        }
+        Context::Literal { triple: true } => (!value.contains("'''")).then(|| value.to_owned()),
+        Context::SingleQuoted => single_line.then(|| value.replace('\'', "''")),
+        Context::Block { .. } => single_line.then(|| value.to_owned()),
+        Context::Comment => unreachable!("placeholders in comments aren't expanded"),
+    }
+}
+
+/* Where a placeholder is in the JSON of an embedded cryptogram, fed the string's characters as
+ * they read once its own escapes are undone.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+struct Embedded {
+    string: bool,
+    escaped: bool,
+}
+
+impl Embedded {
+    fn feed(&mut self, byte: u8) {
+        if self.escaped {
+            self.escaped = false;
+        } else if self.string && byte == b'\\' {
+            self.escaped = true;
+        } else if byte == b'"' {
+            self.string = !self.string;
+        }
+    }
+
+    fn context(&self) -> Context {
+        if self.string {
+            Context::Escaped { triple: false }
+        } else {
+            Context::Bare
+        }
+    }
+}
+
+/* Whether the string or block scalar starting at `idx` is the value of a `cryptogram` key. */
+fn embeds_cryptogram(input: &str, idx: usize) -> bool {
+    let before = input[line_start(input, idx)..idx].trim_end();
+    let Some(key) = before.strip_suffix(['=', ':']) else {
+        return false;
+    };
+    let key = key.trim_end();
+    let key = key.strip_suffix(['"', '\'']).unwrap_or(key);
+    key.strip_suffix("cryptogram").is_some_and(|rest| {
+        !rest.ends_with(|c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    })
+}
+
+fn line_start(input: &str, idx: usize) -> usize {
+    input[..idx].rfind('\n').map_or(0, |newline| newline + 1)
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/* Whether a YAML quote or block indicator at `idx` starts a scalar, rather than being part of a
+ * plain one like `don't`.
+ */
+fn yaml_scalar_start(input: &str, idx: usize) -> bool {
+    let before = &input[line_start(input, idx)..idx];
+    let trimmed = before.trim_end_matches([' ', '\t']);
+    let spaced = trimmed.len() < before.len();
+    match trimmed.chars().last() {
+        None => true,
+        Some('[' | '{' | ',') => true,
+        Some(':' | '-' | '?') => spaced,
+        Some(_) => false,
+    }
+}
+
+/* Expansions
+ *
+ * Where placeholders were replaced, so that a position in the expanded text, eg: from a parse
+ * error, can be traced back to the file as it was written.
+ */
+#[derive(Debug, Default)]
+pub struct Expansions {
+    // (input, output) ranges, in order.
+    replaced: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl Expansions {
+    /* The offset in the input of `offset` in the output. Positions within an expanded value are
+     * those of its placeholder.
+     */
+    pub fn raw_offset(&self, offset: usize) -> usize {
+        let last = self
+            .replaced
+            .iter()
+            .take_while(|(_, output)| output.start <= offset)
+            .last();
+        match last {
+            None => offset,
+            Some((input, output)) if offset < output.end => input.start,
+            Some((input, output)) => input.end + (offset - output.end),
+        }
+    }
+}
+
+pub fn interpolate(
+    input: &str,
+    format: Format,
+) -> Result<(String, Expansions), Vec<InterpolationError>> {
+    expand(input, format, |name| std::env::var(name).ok())
+}
+
+pub fn interpolate_with<F>(
+    input: &str,
+    format: Format,
+    lookup: F,
+) -> Result<String, Vec<InterpolationError>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    expand(input, format, lookup).map(|(output, _)| output)
+}
+
+fn expand<F>(
+    input: &str,
+    format: Format,
+    lookup: F,
+) -> Result<(String, Expansions), Vec<InterpolationError>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let bytes = input.as_bytes();
+    let mut output = String::with_capacity(input.len());
+    let mut expansions = Expansions::default();
+    let mut errors = Vec::new();
+    let mut context = Context::Bare;
+    // The indentation of a line ending in a YAML block indicator, whose block starts on the next,
+    // and whether it holds a cryptogram.
+    let mut block = None;
+    // Set while inside a string holding a cryptogram.
+    let mut embedded: Option<Embedded> = None;
+    // Input before `copied` is already in the output.
+    let mut copied = 0;
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        // Only ASCII is significant, the rest of a multi-byte character is skipped over.
+        if !input.is_char_boundary(idx) {
+            idx += 1;
+            continue;
+        }
+        let rest = &input[idx..];
+        if context != Context::Comment && rest.starts_with('$') {
+            if rest.starts_with("$${") {
+                output.push_str(&input[copied..idx]);
+                let start = output.len();
+                output.push_str("${");
+                expansions
+                    .replaced
+                    .push((idx..idx + 3, start..output.len()));
+                idx += 3;
+                copied = idx;
+                continue;
+            }
+            if let Some(after) = rest.strip_prefix("${") {
+                output.push_str(&input[copied..idx]);
+                let end = match after.find(['}', '\n']) {
+                    Some(end) if after[end..].starts_with('}') => end,
+                    end => {
+                        errors.push(InterpolationError::Unterminated { offset: idx });
+                        idx += 2 + end.unwrap_or(after.len());
+                        copied = idx;
+                        continue;
+                    }
+                };
+
+                let placeholder = &after[..end];
+                let (name, default) = match placeholder.split_once(":-") {
+                    Some((name, default)) => (name, Some(default)),
+                    None => (placeholder, None),
+                };
+                let value = match (lookup(name), default) {
+                    (Some(value), Some(default)) if value.is_empty() => Some(default.to_owned()),
+                    (Some(value), _) => match embedded
+                        .map_or(Some(value.clone()), |json| escape(json.context(), &value))
+                        .and_then(|value| escape(context, &value))
+                    {
+                        Some(escaped) => Some(escaped),
+                        None => {
+                            errors.push(InterpolationError::Unescapable {
+                                name: name.to_owned(),
+                                offset: idx,
+                            });
+                            None
+                        }
+                    },
+                    (None, Some(default)) => Some(default.to_owned()),
+                    (None, None) => {
+                        errors.push(InterpolationError::MissingVariable {
+                            name: name.to_owned(),
+                            offset: idx,
+                        });
+                        None
+                    }
+                };
+                let start = output.len();
+                output.extend(value);
+                let replaced = idx..idx + 2 + end + 1;
+                expansions.replaced.push((replaced, start..output.len()));
+                idx += 2 + end + 1;
+                copied = idx;
+                continue;
+            }
+        }
+
+        let byte = bytes[idx];
+        let mut step = 1;
+        let opened = matches!(context, Context::Bare);
+        match (context, format) {
+            (Context::Comment, _) => {
+                if byte == b'\n' {
+                    context = Context::Bare;
+                }
+            }
+            (Context::Block { indent }, _) if idx == 0 || bytes[idx - 1] == b'\n' => {
+                let line = &rest[..rest.find('\n').unwrap_or(rest.len())];
+                if !line.trim().is_empty() && indentation(line) <= indent {
+                    context = Context::Bare;
+                    step = 0;
+                }
+            }
+            (Context::Block { .. }, _) => {}
+            (Context::Escaped { triple }, _) => match byte {
+                b'\\' => step = 2,
+                b'"' if !triple => context = Context::Bare,
+                b'"' if rest.starts_with("\"\"\"") => {
+                    context = Context::Bare;
+                    step = 3;
+                }
+                _ => {}
+            },
+            (Context::Literal { triple }, _) => {
+                if !triple && byte == b'\'' {
+                    context = Context::Bare;
+                } else if triple && rest.starts_with("'''") {
+                    context = Context::Bare;
+                    step = 3;
+                }
+            }
+            (Context::SingleQuoted, _) => match byte {
+                b'\'' if rest.starts_with("''") => step = 2,
+                b'\'' => context = Context::Bare,
+                _ => {}
+            },
+            (Context::Bare, Format::Toml) => match byte {
+                b'#' => context = Context::Comment,
+                b'"' if rest.starts_with("\"\"\"") => {
+                    context = Context::Escaped { triple: true };
+                    step = 3;
+                }
+                b'"' => context = Context::Escaped { triple: false },
+                b'\'' if rest.starts_with("'''") => {
+                    context = Context::Literal { triple: true };
+                    step = 3;
+                }
+                b'\'' => context = Context::Literal { triple: false },
+                _ => {}
+            },
+            (Context::Bare, Format::Yaml) => match byte {
+                b'#' if idx == 0 || bytes[idx - 1].is_ascii_whitespace() => {
+                    context = Context::Comment;
+                }
+                b'"' if yaml_scalar_start(input, idx) => {
+                    context = Context::Escaped { triple: false };
+                }
+                b'\'' if yaml_scalar_start(input, idx) => context = Context::SingleQuoted,
+                b'|' | b'>' if yaml_scalar_start(input, idx) => {
+                    let indent = indentation(&input[line_start(input, idx)..]);
+                    block = Some((indent, embeds_cryptogram(input, idx)));
+                }
+                _ => {}
+            },
+            (Context::Bare, Format::Json) => {
+                if byte == b'"' {
+                    context = Context::Escaped { triple: false };
+                }
+            }
+        }
+        // Whatever follows a block indicator on its line, the block starts on the next one.
+        if byte == b'\n' && matches!(context, Context::Bare | Context::Comment) {
+            if let Some((indent, cryptogram)) = block.take() {
+                context = Context::Block { indent };
+                embedded = cryptogram.then(Embedded::default);
+                idx += step;
+                continue;
+            }
+        }
+        match context {
+            Context::Bare | Context::Comment => embedded = None,
+            _ if opened => embedded = embeds_cryptogram(input, idx).then(Embedded::default),
+            // An escape in the string stands for the character after the backslash, which is
+            // all that matters to the JSON: `\\` and `\"`.
+            _ => {
+                if let Some(json) = embedded.as_mut() {
+                    json.feed(match step {
+                        2 => bytes.get(idx + 1).copied().unwrap_or(byte),
+                        _ => byte,
+                    });
+                }
+            }
+        }
+        idx += step;
+    }
+    output.push_str(&input[copied.min(input.len())..]);
+
+    if errors.is_empty() {
+        Ok((output, expansions))
+    } else {
+        Err(errors)
+    }
+}
+
+#[test]
+fn interpolate_placeholders() {
+    let lookup = |name: &str| match name {
+        "QUEUE" => Some(String::from("https://sqs/queue")),
+        "EMPTY" => Some(String::new()),
+        "QUOTED" => Some(String::from("a \"b\"\nc = 'd'")),
+        _ => None,
+    };
+
+    assert_eq!(
+        interpolate_with(
+            "url = \"${QUEUE}\"\nport = ${PORT:-8080}\nhost = \"${EMPTY:-localhost}\"\ncost = \"€5, $5, $${QUEUE}\"",
+            Format::Toml,
+            lookup
+        ),
+        Ok(String::from(
+            "url = \"https://sqs/queue\"\nport = 8080\nhost = \"localhost\"\ncost = \"€5, $5, ${QUEUE}\""
+        ))
+    );
+
+    assert_eq!(
+        interpolate_with(
+            "a = 1\nb = \"${MISSING}\"\nc = \"${OPEN\"",
+            Format::Toml,
+            lookup
+        ),
+        Err(vec![
+            InterpolationError::MissingVariable {
+                name: String::from("MISSING"),
+                offset: 11
+            },
+            InterpolationError::Unterminated { offset: 28 },
+        ])
+    );
+
+    // Comments are left alone, and values can't break out of the string they're in.
+    assert_eq!(
+        interpolate_with(
+            "# needs ${MISSING}\na = \"${QUOTED}\" # ${MISSING}\nb = '''${QUOTED}'''",
+            Format::Toml,
+            lookup
+        ),
+        Ok(String::from(
+            "# needs ${MISSING}\na = \"a \\\"b\\\"\\nc = 'd'\" # ${MISSING}\nb = '''a \"b\"\nc = 'd''''"
+        ))
+    );
+    assert_eq!(
+        interpolate_with("a = '${QUOTED}'\nb = ${QUOTED}", Format::Toml, lookup),
+        Err(vec![
+            InterpolationError::Unescapable {
+                name: String::from("QUOTED"),
+                offset: 5
+            },
+            InterpolationError::Unescapable {
+                name: String::from("QUOTED"),
+                offset: 20
+            },
+        ])
+    );
+
+    let yaml = "a: dön't ${QUEUE} # ${MISSING}\nb: 'x ${EMPTY}' # '${MISSING}'\nc: |\n  \"${PORT:-q}\"\n  # ${QUEUE}\nd: \"${QUOTED}\"";
+    assert_eq!(
+        interpolate_with(yaml, Format::Yaml, lookup),
+        Ok(String::from(
+            "a: dön't https://sqs/queue # ${MISSING}\nb: 'x ' # '${MISSING}'\nc: |\n  \"q\"\n  # https://sqs/queue\nd: \"a \\\"b\\\"\\nc = 'd'\""
+        ))
+    );
+    assert!(interpolate_with("c: |\n  ${QUOTED}\n", Format::Yaml, lookup).is_err());
+}
+
+#[test]
+fn interpolate_embedded_cryptograms() {
+    let injection = "a \"b\"\\\"}, \"steps\": [], \"c\": {\"d\nc = 'd'";
+    let lookup = |name: &str| match name {
+        "INJECTION" => Some(String::from(injection)),
+        "PORT" => Some(String::from("8080")),
+        _ => None,
+    };
+    let embedded = |input: &str, format| {
+        let output = interpolate_with(input, format, lookup).unwrap();
+        let document: serde_json::Value = match format {
+            Format::Toml => serde_json::to_value(toml::from_str::<toml::Value>(&output).unwrap()),
+            Format::Yaml => Ok(serde_yaml::from_str(&output).unwrap()),
+            Format::Json => Ok(serde_json::from_str(&output).unwrap()),
+        }
+        .unwrap();
+        let cryptogram = document["cryptogram"].as_str().unwrap();
+        serde_json::from_str::<serde_json::Value>(cryptogram).unwrap()
+    };
+    let expected = serde_json::json!({"a": injection, "port": 8080});
+
+    let toml = r#"cryptogram = """{"a": "${INJECTION}", "port": ${PORT}}""""#;
+    assert_eq!(embedded(toml, Format::Toml), expected);
+    let toml = r#"cryptogram = '''{"a": "${INJECTION}", "port": ${PORT}}'''"#;
+    assert_eq!(embedded(toml, Format::Toml), expected);
+    let yaml = r#"cryptogram: "{\"a\": \"${INJECTION}\", \"port\": ${PORT}}""#;
+    assert_eq!(embedded(yaml, Format::Yaml), expected);
+    let yaml = "cryptogram: |\n  {\"a\": \"${INJECTION}\", \"port\": ${PORT}}\n";
+    assert_eq!(embedded(yaml, Format::Yaml), expected);
+    let json = r#"{"cryptogram": "{\"a\": \"${INJECTION}\", \"port\": ${PORT}}"}"#;
+    assert_eq!(embedded(json, Format::Json), expected);
+
+    // Outside the cryptogram's own strings, the value must be a plain word.
+    let toml = r#"cryptogram = """{"a": ${INJECTION}}""""#;
+    assert!(interpolate_with(toml, Format::Toml, lookup).is_err());
+}
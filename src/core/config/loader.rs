@@ -0,0 +1,993 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use super::{
+    auth::{self, AuthConfig},
+    errors::{ConfigError, ConfigErrors, KeyPath, Location},
+    interpolate::{interpolate, InterpolationError},
+    secret::{self, Secret},
+    source::{Format, Source},
+    validate::{self, Severity},
+    Configuration, EdgeRoute, ServiceDefinition, Services, TlsConfig, Virtualhost, Virtualhosts,
+};
+use crate::model::cryptogram::JsonCryptogram;
+
+/* Loader
+ *
+ * Deserializes each service, virtualhost and route on its own, so that a single mistake
+ * doesn't hide every other problem in the file. Errors are collected and reported together.
+ *
+ * A configuration may span several files: the main file, the files it includes, and standalone
+ * cryptogram files. `origins` remembers which file each part of the configuration came from,
+ * so that problems found afterwards are reported against the right one.
+ *
+ * The selected profile's `services` and `virtualhosts` wait in `overlay` until the entry they
+ * override is read, wherever it is defined. `effective` accumulates the merged document.
+ */
+struct Loader {
+    sources: Vec<Source>,
+    origins: Vec<(KeyPath, usize)>,
+    watched: Vec<PathBuf>,
+    overlay: Map<String, Value>,
+    effective: Map<String, Value>,
+    errors: Vec<ConfigError>,
+}
+
+impl Loader {
+    fn error(
+        &mut self,
+        source: usize,
+        key_path: KeyPath,
+        location: Option<Location>,
+        message: String,
+    ) {
+        let source = &self.sources[source];
+        self.errors.push(ConfigError {
+            file: source.path.clone(),
+            location: location.or_else(|| source.locate(&key_path)),
+            key_path: Some(key_path),
+            message,
+        });
+    }
+
+    fn deserialize<T: DeserializeOwned>(
+        &mut self,
+        source: usize,
+        value: Value,
+        key_path: &KeyPath,
+    ) -> Option<T> {
+        match serde_path_to_error::deserialize(value) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                let key_path = key_path.join(err.path());
+                self.error(source, key_path, None, err.into_inner().to_string());
+                None
+            }
+        }
+    }
+
+    fn entries(&mut self, source: usize, value: Value, key_path: &KeyPath) -> Map<String, Value> {
+        match value {
+            Value::Object(map) => map,
+            other => {
+                let message = format!("invalid type: {}, expected a table", kind(&other));
+                self.error(source, key_path.clone(), None, message);
+                Map::new()
+            }
+        }
+    }
+
+    /* The file a part of the configuration came from: the most specific origin that applies. */
+    fn origin(&self, key_path: &KeyPath) -> usize {
+        self.origins
+            .iter()
+            .filter(|(prefix, _)| key_path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.segments().len())
+            .map(|(_, source)| *source)
+            .unwrap_or(0)
+    }
+
+    /* Read, interpolate and parse a file, returning its index in `sources` and its contents. */
+    fn read(&mut self, path: &str) -> Option<(usize, Value)> {
+        let error = |location: Option<Location>, message: String| ConfigError {
+            file: path.to_owned(),
+            location,
+            key_path: None,
+            message,
+        };
+        // Watched even if it can't be read, so that creating or fixing it triggers a reload.
+        self.watched.push(PathBuf::from(path));
+        let raw = match std::fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(err) => {
+                self.errors.push(error(None, err.to_string()));
+                return None;
+            }
+        };
+        let (text, expansions) = match interpolate(&raw, Format::from_path(path)) {
+            Ok(expanded) => expanded,
+            Err(errors) => {
+                for err in errors {
+                    self.errors.push(interpolation_error(path, &raw, err));
+                }
+                return None;
+            }
+        };
+        let source = Source::new(path, text).expanded_from(raw, expansions);
+        match parse(&source) {
+            Ok(value) => {
+                self.sources.push(source);
+                Some((self.sources.len() - 1, value))
+            }
+            Err(err) => {
+                self.errors.push(err);
+                None
+            }
+        }
+    }
+
+    fn edge_route(
+        &mut self,
+        source: usize,
+        mut value: Value,
+        key_path: &KeyPath,
+    ) -> Option<EdgeRoute> {
+        let mut cryptogram_source = source;
+        match (value.get("cryptogram"), value.get("cryptogram-file")) {
+            (Some(_), Some(_)) => {
+                let message = String::from("set either `cryptogram` or `cryptogram-file`");
+                self.error(source, key_path.key("cryptogram-file"), None, message);
+                return None;
+            }
+            (None, None) => {
+                let message = String::from("missing field `cryptogram` or `cryptogram-file`");
+                self.error(source, key_path.clone(), None, message);
+                return None;
+            }
+            (None, Some(Value::String(file))) => {
+                let path = relative_to(&self.sources[source].path, file);
+                let (file_source, cryptogram) = self.read(&path)?;
+                self.origins.push((key_path.key("cryptogram"), file_source));
+                let _: JsonCryptogram =
+                    self.deserialize(file_source, cryptogram.clone(), &KeyPath::root())?;
+                value["cryptogram"] = cryptogram;
+                cryptogram_source = file_source;
+            }
+            (Some(Value::String(embedded)), None) => {
+                // Parse embedded cryptograms up front, so errors point inside the string.
+                if let Err(err) = JsonCryptogram::from_str(embedded) {
+                    let key_path = key_path.key("cryptogram");
+                    let location =
+                        self.sources[source].locate_embedded(&key_path, err.line(), err.column());
+                    let message = match location {
+                        Some(_) => without_position(err.to_string()),
+                        None => err.to_string(),
+                    };
+                    self.error(source, key_path, location, message);
+                    return None;
+                }
+            }
+            _ => {}
+        }
+        let mut edge_route: EdgeRoute = self.deserialize(source, value, key_path)?;
+        let resolved = self.secrets(
+            cryptogram_source,
+            &mut edge_route.cryptogram,
+            &key_path.key("cryptogram"),
+        );
+        resolved.then_some(edge_route)
+    }
+
+    /* Resolve the secret references in a cryptogram's headers, relative to the file it's in. */
+    fn secrets(
+        &mut self,
+        source: usize,
+        cryptogram: &mut JsonCryptogram,
+        key_path: &KeyPath,
+    ) -> bool {
+        let secrets =
+            cryptogram
+                .steps
+                .iter_mut()
+                .enumerate()
+                .flat_map(|(idx, step)| {
+                    let headers_path = key_path.key("steps").index(idx).key("headers");
+                    step.headers.iter_mut().flatten().enumerate().map(
+                        move |(header_idx, (_, secret))| (headers_path.index(header_idx), secret),
+                    )
+                })
+                .collect();
+        self.resolve(source, secrets)
+    }
+
+    /* Resolve secret references relative to the file they're in, watching the files they name. */
+    fn resolve(&mut self, source: usize, secrets: Vec<(KeyPath, &mut Secret)>) -> bool {
+        let dir = Path::new(&self.sources[source].path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let mut resolved = true;
+        for (key_path, secret) in secrets {
+            if let Some(file) = secret.file() {
+                self.watched.push(dir.join(file));
+            }
+            if let Err(message) = secret.resolve(&dir) {
+                self.error(source, key_path, None, message);
+                resolved = false;
+            }
+        }
+        resolved
+    }
+
+    /* Certificate and key paths are relative to the file they're in. */
+    fn tls<'a>(&self, source: usize, tls: impl IntoIterator<Item = &'a mut TlsConfig>) {
+        let dir = Path::new(&self.sources[source].path)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        for tls in tls {
+            tls.certificate = dir.join(&tls.certificate);
+            tls.key = dir.join(&tls.key);
+        }
+    }
+
+    fn virtualhost(
+        &mut self,
+        source: usize,
+        mut value: Value,
+        key_path: &KeyPath,
+    ) -> Option<Virtualhost> {
+        let routes = value
+            .get_mut("routes")
+            .map(|routes| std::mem::replace(routes, Value::Object(Map::new())));
+        let mut vhost: Option<Virtualhost> = self.deserialize(source, value, key_path);
+        self.tls(source, vhost.iter_mut().flat_map(|vhost| &mut vhost.tls));
+
+        let routes_path = key_path.key("routes");
+        let mut edge_routes = hashbrown::HashMap::new();
+        if let Some(routes) = routes {
+            for (route, value) in self.entries(source, routes, &routes_path) {
+                if let Some(edge_route) = self.edge_route(source, value, &routes_path.key(&route)) {
+                    edge_routes.insert(route, edge_route);
+                }
+            }
+        }
+
+        vhost.map(|vhost| Virtualhost {
+            routes: edge_routes,
+            ..vhost
+        })
+    }
+
+    /* Claim a service or virtualhost name for `source`, unless another file got there first. */
+    fn claim(&mut self, source: usize, key_path: &KeyPath) -> bool {
+        let existing = self
+            .origins
+            .iter()
+            .find(|(claimed, _)| claimed == key_path)
+            .map(|(_, existing)| *existing);
+        match existing {
+            Some(existing) => {
+                let message = format!("already defined in {}", self.sources[existing].path);
+                self.error(source, key_path.clone(), None, message);
+                false
+            }
+            None => {
+                self.origins.push((key_path.clone(), source));
+                true
+            }
+        }
+    }
+
+    /* Remove `profiles` from the document, and merge in the selected one. */
+    fn profile(&mut self, source: usize, document: &mut Map<String, Value>, profile: Option<&str>) {
+        let profiles_path = KeyPath::root().key("profiles");
+        let mut profiles = document
+            .remove("profiles")
+            .map(|profiles| self.entries(source, profiles, &profiles_path))
+            .unwrap_or_default();
+        let Some(name) = profile else {
+            return;
+        };
+        let Some(overlay) = profiles.remove(name) else {
+            let message = format!("unknown profile {:?}", name);
+            self.error(source, profiles_path, None, message);
+            return;
+        };
+        let overlay_path = profiles_path.key(name);
+        let mut overlay = self.entries(source, overlay, &overlay_path);
+        self.sources[source].overlay = Some(overlay_path);
+        for section in ["services", "virtualhosts"] {
+            if let Some(entries) = overlay.remove(section) {
+                self.overlay.insert(section.to_owned(), entries);
+            }
+        }
+        merge(document, overlay);
+    }
+
+    /* Apply the profile's overrides for one service or virtualhost, and record the result. */
+    fn overlaid(&mut self, section: &str, name: &str, mut value: Value) -> Value {
+        let overlay = self
+            .overlay
+            .get_mut(section)
+            .and_then(Value::as_object_mut)
+            .and_then(|entries| entries.remove(name));
+        match (&mut value, overlay) {
+            (Value::Object(entries), Some(Value::Object(overlay))) => merge(entries, overlay),
+            (_, Some(overlay)) => value = overlay,
+            (_, None) => {}
+        }
+        if let Value::Object(entries) = self
+            .effective
+            .entry(section)
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            entries.insert(name.to_owned(), value.clone());
+        }
+        value
+    }
+
+    /* Deserialize the `services` and `virtualhosts` of one file into the combined maps. */
+    fn sections(
+        &mut self,
+        source: usize,
+        services: Option<Value>,
+        virtualhosts: Option<Value>,
+        service_definitions: &mut Services,
+        vhosts: &mut Virtualhosts,
+    ) {
+        let services_path = KeyPath::root().key("services");
+        for (name, value) in services
+            .map(|services| self.entries(source, services, &services_path))
+            .unwrap_or_default()
+        {
+            let key_path = services_path.key(&name);
+            if !self.claim(source, &key_path) {
+                continue;
+            }
+            let value = self.overlaid("services", &name, value);
+            let service: Option<ServiceDefinition> = self.deserialize(source, value, &key_path);
+            if let Some(mut service) = service {
+                let ServiceDefinition::Rest { auth, .. } = &mut service;
+                let secrets = auth
+                    .iter_mut()
+                    .flat_map(AuthConfig::secrets_mut)
+                    .map(|(field, secret)| (key_path.key("auth").key(field), secret))
+                    .collect();
+                if self.resolve(source, secrets) {
+                    service_definitions.insert(name, service);
+                }
+            }
+        }
+
+        let virtualhosts_path = KeyPath::root().key("virtualhosts");
+        for (name, value) in virtualhosts
+            .map(|virtualhosts| self.entries(source, virtualhosts, &virtualhosts_path))
+            .unwrap_or_default()
+        {
+            let key_path = virtualhosts_path.key(&name);
+            if !self.claim(source, &key_path) {
+                continue;
+            }
+            let value = self.overlaid("virtualhosts", &name, value);
+            if let Some(vhost) = self.virtualhost(source, value, &key_path) {
+                vhosts.insert(name, vhost);
+            }
+        }
+    }
+
+    /* Expand the `include` globs, relative to the main file. */
+    fn includes(&mut self, patterns: &[String]) -> Vec<String> {
+        let mut paths = Vec::new();
+        for (idx, pattern) in patterns.iter().enumerate() {
+            let pattern = relative_to(&self.sources[0].path, pattern);
+            self.watched.push(glob_base(&pattern));
+            match glob::glob(&pattern) {
+                Ok(matches) => paths.extend(
+                    matches
+                        .flatten()
+                        .map(|path| path.to_string_lossy().into_owned()),
+                ),
+                Err(err) => {
+                    let key_path = KeyPath::root().key("include").index(idx);
+                    self.error(0, key_path, None, err.to_string());
+                }
+            }
+        }
+        paths
+    }
+
+    fn configuration(
+        &mut self,
+        source: usize,
+        value: Value,
+        profile: Option<&str>,
+    ) -> Option<Configuration> {
+        let root = KeyPath::root();
+        let mut document = self.entries(source, value, &root);
+        self.profile(source, &mut document, profile);
+
+        // Swap out the maps we deserialize entry-by-entry, leaving empty tables behind so
+        // that the remaining top-level settings can be deserialized as usual.
+        let mut take = |name: &str| {
+            document
+                .get_mut(name)
+                .map(|value| std::mem::replace(value, Value::Object(Map::new())))
+        };
+        let services = take("services");
+        let virtualhosts = take("virtualhosts");
+        self.effective = document.clone();
+
+        let mut config: Option<Configuration> =
+            self.deserialize(source, Value::Object(document), &root);
+        if let Some(config) = config.as_mut() {
+            let listeners = config.http.listeners.iter_mut();
+            let tls = listeners.flat_map(|listener| &mut listener.tls);
+            self.tls(source, config.http.tls.iter_mut().chain(tls));
+        }
+
+        let mut service_definitions = hashbrown::HashMap::new();
+        let mut vhosts = hashbrown::HashMap::new();
+        self.sections(
+            source,
+            services,
+            virtualhosts,
+            &mut service_definitions,
+            &mut vhosts,
+        );
+
+        let patterns = config
+            .as_ref()
+            .map(|config| config.include.clone())
+            .unwrap_or_default();
+        for path in self.includes(&patterns) {
+            let Some((included, value)) = self.read(&path) else {
+                continue;
+            };
+            let mut document = self.entries(included, value, &root);
+            let services = document.remove("services");
+            let virtualhosts = document.remove("virtualhosts");
+            for key in document.keys() {
+                let message = String::from("included files may only set services and virtualhosts");
+                self.error(included, root.key(key), None, message);
+            }
+            self.sections(
+                included,
+                services,
+                virtualhosts,
+                &mut service_definitions,
+                &mut vhosts,
+            );
+        }
+
+        // Whatever is left of the profile defines services and virtualhosts of its own.
+        let mut overlay = std::mem::take(&mut self.overlay);
+        self.sections(
+            source,
+            overlay.remove("services"),
+            overlay.remove("virtualhosts"),
+            &mut service_definitions,
+            &mut vhosts,
+        );
+
+        config.map(|config| Configuration {
+            services: service_definitions,
+            virtualhosts: vhosts,
+            sources: std::mem::take(&mut self.watched),
+            ..config
+        })
+    }
+}
+
+/* Inline credentials are left out of the effective configuration. */
+fn redact(effective: &mut Map<String, Value>) {
+    let routes = effective
+        .get_mut("virtualhosts")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|virtualhosts| virtualhosts.values_mut())
+        .filter_map(|vhost| vhost.get_mut("routes").and_then(Value::as_object_mut))
+        .flat_map(|routes| routes.values_mut());
+    for route in routes {
+        if let Some(cryptogram) = route.get_mut("cryptogram") {
+            secret::redact_cryptogram(cryptogram);
+        }
+    }
+
+    let auths = effective
+        .get_mut("services")
+        .and_then(Value::as_object_mut)
+        .into_iter()
+        .flat_map(|services| services.values_mut())
+        .filter_map(|service| service.get_mut("auth"));
+    for auth in auths {
+        auth::redact(auth);
+    }
+}
+
+/* Tables are merged key by key, anything else in the overlay replaces the base value. */
+fn merge(base: &mut Map<String, Value>, overlay: Map<String, Value>) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(base)), Value::Object(overlay)) => merge(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "table",
+    }
+}
+
+/* Resolve a path found in `file` relative to the directory `file` is in. */
+fn relative_to(file: &str, path: &str) -> String {
+    match Path::new(file).parent() {
+        Some(dir) if Path::new(path).is_relative() => dir.join(path).to_string_lossy().into_owned(),
+        _ => path.to_owned(),
+    }
+}
+
+/* The directory a glob searches, so the watcher notices files being added or removed. */
+fn glob_base(pattern: &str) -> PathBuf {
+    let is_glob = |component: &Component| {
+        component
+            .as_os_str()
+            .to_string_lossy()
+            .contains(['*', '?', '['])
+    };
+    let mut base: PathBuf = Path::new(pattern)
+        .components()
+        .take_while(|component| !is_glob(component))
+        .collect();
+    if base == Path::new(pattern) {
+        base.pop();
+    }
+    base
+}
+
+fn interpolation_error(path: &str, raw: &str, err: InterpolationError) -> ConfigError {
+    let offset = match err {
+        InterpolationError::MissingVariable { offset, .. } => offset,
+        InterpolationError::Unterminated { offset } => offset,
+        InterpolationError::Unescapable { offset, .. } => offset,
+    };
+    ConfigError {
+        file: path.to_owned(),
+        location: Some(Location::from_offset(raw, offset)),
+        key_path: None,
+        message: err.to_string(),
+    }
+}
+
+/* Strip the position serde_json and serde_yaml append to their messages, we report our own. */
+fn without_position(message: String) -> String {
+    match message.rfind(" at line ") {
+        Some(idx) => message[..idx].to_owned(),
+        None => message,
+    }
+}
+
+fn parse(source: &Source) -> Result<Value, ConfigError> {
+    let (location, message) = match source.format {
+        Format::Toml => match toml::from_str(&source.text) {
+            Ok(value) => return Ok(value),
+            Err(err) => (
+                err.span().map(|span| source.location_of(span.start)),
+                err.message().to_owned(),
+            ),
+        },
+        Format::Yaml => match serde_yaml::from_str(&source.text) {
+            Ok(value) => return Ok(value),
+            Err(err) => (
+                err.location()
+                    .map(|location| source.location_of(location.index())),
+                without_position(err.to_string()),
+            ),
+        },
+        Format::Json => match serde_json::from_str(&source.text) {
+            Ok(value) => return Ok(value),
+            Err(err) => (
+                Some(source.location_at(err.line(), err.column())),
+                without_position(err.to_string()),
+            ),
+        },
+    };
+    Err(ConfigError {
+        file: source.path.clone(),
+        location,
+        key_path: None,
+        message,
+    })
+}
+
+/* Load a configuration, along with the effective document after merging includes and the
+ * selected profile.
+ */
+pub fn load(path: &str, profile: Option<&str>) -> Result<(Configuration, Value), ConfigErrors> {
+    let mut loader = Loader {
+        sources: Vec::new(),
+        origins: Vec::new(),
+        watched: Vec::new(),
+        overlay: Map::new(),
+        effective: Map::new(),
+        errors: Vec::new(),
+    };
+    let config = match loader.read(path) {
+        Some((source, value)) => loader.configuration(source, value, profile),
+        None => None,
+    };
+
+    if let Some(config) = config.as_ref() {
+        for issue in validate::configuration(config) {
+            let source = &loader.sources[loader.origin(&issue.key_path)];
+            let error = ConfigError {
+                file: source.path.clone(),
+                location: source.locate(&issue.key_path),
+                key_path: Some(issue.key_path),
+                message: issue.message,
+            };
+            match issue.severity {
+                Severity::Error => loader.errors.push(error),
+                Severity::Warning => log::warn!("{}", error),
+            }
+        }
+    }
+    let files: Vec<String> = loader
+        .sources
+        .iter()
+        .map(|source| source.path.clone())
+        .collect();
+    let mut errors = loader.errors;
+    errors.sort_by_key(|err| {
+        (
+            files.iter().position(|file| *file == err.file),
+            err.location.map(|l| (l.line, l.column)),
+        )
+    });
+
+    redact(&mut loader.effective);
+    match config {
+        Some(config) if errors.is_empty() => Ok((config, Value::Object(loader.effective))),
+        Some(config) => Err(ConfigErrors {
+            errors,
+            sources: config.sources,
+        }),
+        None => Err(ConfigErrors {
+            errors,
+            sources: loader.watched,
+        }),
+    }
+}
+
+#[test]
+fn load_yaml_and_json() {
+    let dir = std::env::temp_dir().join(format!("delegator-loader-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let yaml = dir.join("edge.yaml");
+    std::fs::write(
+        &yaml,
+        r#"
+events:
+  user_action: { queue_url: noop }
+http:
+  host: 0.0.0.0
+  port: 8080
+  client: { user-agent: delegator, default-timeout: 30s }
+services:
+  catalog:
+    protocol: rest
+    scheme: http
+    authority: localhost:8080
+    methods:
+      explore: { path: /explore/, method: POST }
+virtualhosts:
+  catalog:
+    hostname: localhost
+    routes:
+      /explore:
+        cryptogram:
+          steps:
+            - service: catalog
+              method: explore
+              postflight: '{"results": .results}'
+"#,
+    )
+    .unwrap();
+    let config = load(yaml.to_str().unwrap(), None).unwrap().0;
+    let route = &config.virtualhosts["catalog"].routes["/explore"];
+    assert_eq!(
+        route.cryptogram.steps[0].service.as_deref(),
+        Some("catalog")
+    );
+
+    let json = dir.join("edge.json");
+    let text = std::fs::read_to_string(&yaml).unwrap();
+    let mut value: Value = serde_yaml::from_str(&text).unwrap();
+    value["virtualhosts"]["catalog"]["routes"]["/explore"]["cryptogram"]["steps"][0]["method"] =
+        Value::from("search");
+    std::fs::write(&json, serde_json::to_string_pretty(&value).unwrap()).unwrap();
+    let errors = load(json.to_str().unwrap(), None).unwrap_err();
+    assert_eq!(
+        errors.to_string(),
+        format!(
+            "{}: virtualhosts.catalog.routes.\"/explore\".cryptogram.steps[0].method: unknown method \"search\" for service \"catalog\"",
+            json.display()
+        )
+    );
+
+    std::fs::write(&json, "{\n  \"http\": }").unwrap();
+    let errors = load(json.to_str().unwrap(), None).unwrap_err();
+    assert_eq!(
+        errors.errors[0].location,
+        Some(Location {
+            line: 2,
+            column: 11
+        })
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn load_includes() {
+    let dir = std::env::temp_dir().join(format!("delegator-includes-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+
+    let main = dir.join("edge.toml");
+    std::fs::write(
+        &main,
+        r#"
+include = ["conf.d/*.toml"]
+
+[events.user_action]
+queue_url = "noop"
+
+[http]
+host = "0.0.0.0"
+port = 8080
+client = { user-agent = "delegator", default-timeout = "30s" }
+
+[services.catalog]
+protocol = "rest"
+scheme = "http"
+authority = "localhost:8080"
+methods.explore = { path = "/explore/", method = "POST" }
+"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("conf.d/catalog.toml"),
+        r#"
+[virtualhosts.catalog]
+hostname = "localhost"
+routes."/explore" = { cryptogram-file = "explore.cryptogram.json" }
+"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("conf.d/explore.cryptogram.json"),
+        r#"{"steps": [{"service": "catalog", "method": "explore"}]}"#,
+    )
+    .unwrap();
+
+    let config = load(main.to_str().unwrap(), None).unwrap().0;
+    let route = &config.virtualhosts["catalog"].routes["/explore"];
+    assert_eq!(route.cryptogram.steps[0].method.as_deref(), Some("explore"));
+    assert!(config.sources.contains(&dir.join("conf.d")));
+    assert!(config
+        .sources
+        .contains(&dir.join("conf.d/explore.cryptogram.json")));
+
+    let pricing = dir.join("conf.d/pricing.toml");
+    std::fs::write(
+        &pricing,
+        r#"
+[services.catalog]
+protocol = "rest"
+scheme = "http"
+authority = "localhost:8081"
+methods = {}
+"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("conf.d/explore.cryptogram.json"),
+        r#"{"steps": [{"service": "catalog", "method": "search"}]}"#,
+    )
+    .unwrap();
+    let errors: Vec<String> = load(main.to_str().unwrap(), None)
+        .unwrap_err()
+        .errors
+        .into_iter()
+        .map(|err| format!("{} {}", err.file, err.message))
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            format!(
+                "{} unknown method \"search\" for service \"catalog\"",
+                dir.join("conf.d/explore.cryptogram.json").display()
+            ),
+            format!(
+                "{} already defined in {}",
+                pricing.display(),
+                main.display()
+            ),
+        ]
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn load_profiles() {
+    let dir = std::env::temp_dir().join(format!("delegator-profiles-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("conf.d")).unwrap();
+
+    let main = dir.join("edge.toml");
+    std::fs::write(
+        &main,
+        r#"include = ["conf.d/*.toml"]
+
+[events.user_action]
+queue_url = "noop"
+
+[http]
+host = "0.0.0.0"
+port = 8080
+client = { user-agent = "delegator", default-timeout = "30s" }
+
+[services.catalog]
+protocol = "rest"
+scheme = "http"
+authority = "localhost:8080"
+methods.explore = { path = "/explore/", method = "POST" }
+
+[profiles.production]
+http.port = 80
+
+[profiles.production.services.catalog]
+authority = "catalog.internal"
+
+[profiles.production.services.pricing]
+authority = "pricing.internal"
+
+[profiles.production.services.apex]
+protocol = "rest"
+scheme = "https"
+authority = "apex.internal"
+methods = {}
+
+[profiles.broken]
+http.port = "eighty"
+"#,
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("conf.d/pricing.toml"),
+        r#"
+[services.pricing]
+protocol = "rest"
+scheme = "http"
+authority = "localhost:8081"
+methods = {}
+"#,
+    )
+    .unwrap();
+    let main = main.to_str().unwrap();
+
+    let (config, _) = load(main, None).unwrap();
+    assert_eq!(config.http.port, Some(8080));
+    assert_eq!(config.services.len(), 2);
+
+    let (config, effective) = load(main, Some("production")).unwrap();
+    let authority = |name: &str| match &config.services[name] {
+        ServiceDefinition::Rest { authorities, .. } => authorities[0].authority.to_string(),
+    };
+    assert_eq!(config.http.port, Some(80));
+    assert_eq!(authority("catalog"), "catalog.internal");
+    assert_eq!(authority("pricing"), "pricing.internal");
+    assert_eq!(authority("apex"), "apex.internal");
+    assert_eq!(effective["services"]["catalog"]["scheme"], "http");
+    assert!(effective.get("profiles").is_none());
+
+    let errors = load(main, Some("broken")).unwrap_err();
+    assert_eq!(errors.errors[0].location.map(|l| l.line), Some(33));
+    let errors = load(main, Some("staging")).unwrap_err();
+    assert_eq!(errors.errors[0].message, "unknown profile \"staging\"");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn load_secrets() {
+    let dir = std::env::temp_dir().join(format!("delegator-secrets-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("token"), "Bearer s3cr3t\n").unwrap();
+    let variable = format!("DELEGATOR_TEST_SECRET_{}", std::process::id());
+    std::env::set_var(&variable, "k3y");
+
+    let main = dir.join("edge.yaml");
+    let write = |variable: &str| {
+        let text = format!(
+            r#"
+events:
+  user_action: {{ queue_url: noop }}
+http:
+  host: 0.0.0.0
+  port: 8080
+  client: {{ user-agent: delegator, default-timeout: 30s }}
+services:
+  catalog:
+    protocol: rest
+    scheme: http
+    authority: localhost:8080
+    methods:
+      explore: {{ path: /explore/, method: POST }}
+virtualhosts:
+  catalog:
+    hostname: localhost
+    routes:
+      /explore:
+        cryptogram:
+          steps:
+            - service: catalog
+              method: explore
+              headers:
+                - [authorization, {{ file: token }}]
+                - [x-api-key, {{ env: {} }}]
+                - [x-tenant, hunter2]
+"#,
+            variable
+        );
+        std::fs::write(&main, text).unwrap();
+    };
+    write(&variable);
+
+    let (config, effective) = load(main.to_str().unwrap(), None).unwrap();
+    let headers = config.virtualhosts["catalog"].routes["/explore"]
+        .cryptogram
+        .steps[0]
+        .headers
+        .clone()
+        .unwrap();
+    let values: Vec<Option<&str>> = headers.iter().map(|(_, secret)| secret.expose()).collect();
+    assert_eq!(
+        values,
+        vec![Some("Bearer s3cr3t"), Some("k3y"), Some("hunter2")]
+    );
+    assert!(config.sources.contains(&dir.join("token")));
+
+    let logged = format!(
+        "{:?} {}",
+        headers,
+        serde_json::to_string(&effective).unwrap()
+    );
+    for value in ["s3cr3t", "k3y", "hunter2"] {
+        assert!(!logged.contains(value), "{}", logged);
+    }
+
+    write("DELEGATOR_TEST_SECRET_UNSET");
+    let errors = load(main.to_str().unwrap(), None).unwrap_err();
+    assert_eq!(
+        errors.errors[0].message,
+        "environment variable DELEGATOR_TEST_SECRET_UNSET is not set"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,46 +1,178 @@
+pub mod auth;
+pub mod bulkhead;
+pub mod circuit_breaker;
+pub mod cors;
+pub mod errors;
 pub mod events;
+pub mod health;
 pub mod http_method;
+pub mod interpolate;
+mod loader;
 pub mod path_and_query;
+pub mod path_template;
+pub mod request_mapping;
+pub mod retry;
+pub mod schema;
 pub mod scheme;
-mod stringy_duration;
+pub mod secret;
+mod source;
+pub(crate) mod stringy_duration;
+pub mod upstream;
+pub mod validate;
 
-use std::time::Duration;
+use std::{collections::BTreeMap, path::PathBuf, str::FromStr, time::Duration};
 
-use actix_web::http::{
-    uri::{Authority, PathAndQuery, Scheme},
-    Method,
-};
+use actix_web::http::{uri::Scheme, Method};
 use hashbrown::HashMap;
 
-use serde::{Deserialize, Deserializer};
-
-use toml;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject, SubschemaValidation},
+    JsonSchema,
+};
+use serde::{
+    de::{value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
 
+use self::auth::AuthConfig;
+use self::bulkhead::BulkheadConfig;
+use self::circuit_breaker::CircuitBreakerConfig;
+use self::cors::CorsConfig;
+use self::errors::ConfigErrors;
 use self::events::EventConfig;
+use self::health::HealthConfig;
+use self::path_template::PathTemplate;
+use self::request_mapping::{FieldPath, MappingError, OutgoingRequest, RequestBody};
+use self::retry::RetryPolicy;
+use self::upstream::{Balance, Upstream};
 use crate::model::cryptogram::JsonCryptogram;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct HttpClientConfig {
-    #[serde(alias = "user-agent")]
+    #[serde(rename = "user-agent", alias = "user_agent")]
     pub user_agent: String,
-    #[serde(alias = "default-timeout", with = "stringy_duration")]
+    #[serde(
+        rename = "default-timeout",
+        alias = "default_timeout",
+        with = "stringy_duration"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
     pub default_timeout: Duration,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+pub struct TlsConfig {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+fn default_evaluate() -> bool {
+    true
+}
+
+/* ListenerConfig
+ *
+ * Somewhere the edge accepts connections: either a TCP `host` and `port`, or a Unix domain
+ * `socket`. A listener serves the routes of the `virtualhosts` it names (default: all of them),
+ * and `/evaluate` unless `evaluate = false`.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct ListenerConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub socket: Option<PathBuf>,
+    pub tls: Option<TlsConfig>,
+    pub virtualhosts: Option<Vec<String>>,
+    #[serde(default = "default_evaluate")]
+    pub evaluate: bool,
+}
+
+impl ListenerConfig {
+    pub fn serves(&self, virtualhost: &str) -> bool {
+        match &self.virtualhosts {
+            Some(names) => names.iter().any(|name| name == virtualhost),
+            None => true,
+        }
+    }
+}
+
+impl std::fmt::Display for ListenerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.socket, &self.host, self.port) {
+            (Some(socket), _, _) => write!(f, "unix:{}", socket.display()),
+            (None, Some(host), Some(port)) => write!(f, "{}:{}", host, port),
+            _ => f.write_str("(incomplete listener)"),
+        }
+    }
+}
+
+/* HttpConfig
+ *
+ * `host`, `port` and `tls` describe a single listener serving everything; `listeners` replaces
+ * them when the edge should listen in more than one place.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct HttpConfig {
     pub client: HttpClientConfig,
-    pub host: String,
-    pub port: u16,
-    pub cors: Vec<String>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    pub tls: Option<TlsConfig>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+}
+
+impl HttpConfig {
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+        vec![ListenerConfig {
+            host: self.host.clone(),
+            port: self.port,
+            socket: None,
+            tls: self.tls.clone(),
+            virtualhosts: None,
+            evaluate: true,
+        }]
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/* MethodDefinition
+ *
+ * `query` and `headers` map parameter and header names to fields of the outgoing payload,
+ * `body` picks what is sent as the JSON body, and `retry` how failed calls are attempted
+ * again, eg: for a GET that takes no body:
+ *
+ *   path = "/users/{user_id}/closets"
+ *   method = "GET"
+ *   query = { limit = "page.limit" }
+ *   body = "none"
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct MethodDefinition {
-    #[serde(with = "path_and_query")]
-    pub path: PathAndQuery,
+    pub path: PathTemplate,
     #[serde(with = "http_method")]
+    #[schemars(schema_with = "http_method::schema")]
     pub method: Method,
+    #[serde(default, deserialize_with = "stringy_duration::option::deserialize")]
+    #[schemars(schema_with = "stringy_duration::option::schema")]
+    pub timeout: Option<Duration>,
+    #[serde(default)]
+    pub query: BTreeMap<String, FieldPath>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, FieldPath>,
+    #[serde(default)]
+    pub body: RequestBody,
+    pub retry: Option<RetryPolicy>,
+}
+
+impl MethodDefinition {
+    pub fn request(&self, payload: &serde_json::Value) -> Result<OutgoingRequest, MappingError> {
+        request_mapping::request(&self.path, &self.query, &self.headers, &self.body, payload)
+    }
 }
 
 /* ServiceDefinition
@@ -50,56 +182,155 @@ pub struct MethodDefinition {
  *
  * NB: Attempting to use "untagged" deserializing obscured underlying errors.
  */
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 #[serde(tag = "protocol")]
 pub enum ServiceDefinition {
     #[serde(rename(serialize = "rest", deserialize = "rest"))]
     Rest {
         #[serde(with = "scheme")]
+        #[schemars(schema_with = "scheme::schema")]
         scheme: Scheme,
-        #[serde(with = "http_serde::authority")]
-        authority: Authority,
+        #[serde(rename = "authority", deserialize_with = "upstream::deserialize")]
+        #[schemars(rename = "authority", schema_with = "upstream::schema")]
+        authorities: Vec<Upstream>,
+        #[serde(default)]
+        balance: Balance,
+        #[schemars(with = "std::collections::HashMap<String, MethodDefinition>")]
         methods: HashMap<String, MethodDefinition>,
         virtualhosts: Option<Vec<String>>,
+        #[serde(default, deserialize_with = "stringy_duration::option::deserialize")]
+        #[schemars(schema_with = "stringy_duration::option::schema")]
+        timeout: Option<Duration>,
+        auth: Option<AuthConfig>,
+        health: Option<HealthConfig>,
+        #[serde(rename = "circuit-breaker", alias = "circuit_breaker")]
+        circuit_breaker: Option<CircuitBreakerConfig>,
+        bulkhead: Option<BulkheadConfig>,
     },
 }
 
+struct CryptogramVisitor;
+
+impl<'de> Visitor<'de> for CryptogramVisitor {
+    type Value = JsonCryptogram;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("EXPECTED: a cryptogram, or a string containing one as JSON")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        JsonCryptogram::from_str(v).map_err(E::custom)
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        JsonCryptogram::deserialize(MapAccessDeserializer::new(map))
+    }
+}
+
+/* Cryptograms are embedded as JSON strings in TOML, and may be written out as native
+ * structures in YAML and JSON.
+ */
 fn decode_cryptogram<'de, D>(deserializer: D) -> Result<JsonCryptogram, D::Error>
 where
     D: Deserializer<'de>,
 {
-    use serde::de::Error;
-    use std::str::FromStr;
+    deserializer.deserialize_any(CryptogramVisitor)
+}
 
-    let s = String::deserialize(deserializer)?;
-    JsonCryptogram::from_str(&s).map_err(Error::custom)
+fn cryptogram_schema(gen: &mut SchemaGenerator) -> Schema {
+    let encoded = SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(String::from("A JSON-encoded cryptogram")),
+            ..Default::default()
+        })),
+        instance_type: Some(InstanceType::String.into()),
+        ..Default::default()
+    };
+    SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![gen.subschema_for::<JsonCryptogram>(), encoded.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
 }
 
-#[derive(Clone, Debug, Deserialize)]
+/* EdgeRoute
+ *
+ * The cryptogram is either inline, or read from a standalone file at load time (relative to the
+ * file declaring the route), in which case `cryptogram_file` records where it came from.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct EdgeRoute {
-    #[serde(deserialize_with = "decode_cryptogram")]
+    #[serde(default, deserialize_with = "decode_cryptogram")]
+    #[schemars(schema_with = "cryptogram_schema")]
     pub cryptogram: JsonCryptogram,
+    #[serde(rename = "cryptogram-file", alias = "cryptogram_file")]
+    pub cryptogram_file: Option<PathBuf>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct Virtualhost {
     pub hostname: String,
+    #[schemars(with = "std::collections::HashMap<String, EdgeRoute>")]
     pub routes: HashMap<String, EdgeRoute>,
+    pub cors: Option<CorsConfig>,
+    pub tls: Option<TlsConfig>,
 }
 
 pub type Services = HashMap<String, ServiceDefinition>;
 pub type Virtualhosts = HashMap<String, Virtualhost>;
 
-#[derive(Clone, Debug, Deserialize)]
+/* Configuration
+ *
+ * `include` globs name further files, relative to this one, whose `services` and `virtualhosts`
+ * are merged in. `sources` lists every file and include directory that was read, for reloading.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct Configuration {
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub sources: Vec<PathBuf>,
     pub http: HttpConfig,
+    #[serde(default)]
+    #[schemars(with = "std::collections::HashMap<String, ServiceDefinition>")]
     pub services: Services,
     pub events: EventConfig,
+    #[serde(default)]
+    #[schemars(with = "std::collections::HashMap<String, Virtualhost>")]
     pub virtualhosts: Virtualhosts,
 }
 
-pub fn load_file(path: &str) -> Result<Configuration, std::io::Error> {
-    let config_str = std::fs::read_to_string(path)?;
-    let config: Configuration = toml::from_str(&config_str).unwrap();
-    Ok(config)
+pub fn load_file(path: &str, profile: Option<&str>) -> Result<Configuration, ConfigErrors> {
+    loader::load(path, profile).map(|(config, _)| config)
+}
+
+/* The configuration as it is actually used, with includes and the selected profile merged in,
+ * rendered in the same format as the main file.
+ */
+pub fn effective(path: &str, profile: Option<&str>) -> Result<String, ConfigErrors> {
+    let (_, document) = loader::load(path, profile)?;
+    source::Format::from_path(path)
+        .render(&document)
+        .map_err(|message| {
+            let error = errors::ConfigError {
+                file: path.to_owned(),
+                location: None,
+                key_path: None,
+                message,
+            };
+            ConfigErrors {
+                errors: vec![error],
+                sources: vec![PathBuf::from(path)],
+            }
+        })
 }
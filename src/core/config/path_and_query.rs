@@ -1,4 +1,8 @@
 use actix_web::http::uri::PathAndQuery;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject, StringValidation},
+};
 use serde::{de::Visitor, Deserializer};
 
 struct ConfigPathAndQueryVisitor;
@@ -24,3 +28,15 @@ where
 {
     de.deserialize_str(ConfigPathAndQueryVisitor)
 }
+
+pub fn schema(_gen: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: Some(String::from("^/")),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
@@ -0,0 +1,196 @@
+use std::str::FromStr;
+
+use actix_web::http::uri::PathAndQuery;
+use percent_encoding::utf8_percent_encode;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject, StringValidation},
+    JsonSchema,
+};
+use serde::{de::Visitor, Deserialize, Deserializer};
+use serde_json::Value;
+
+use super::request_mapping::{FieldPath, MappingError, COMPONENT};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(FieldPath),
+}
+
+/* PathTemplate
+ *
+ * A method's path and query, with `{placeholders}` filled in from the outgoing payload, eg:
+ * "/users/{id}/closets/{closet.id}". Dotted names reach into nested objects. Values must be
+ * strings, numbers or booleans, and are percent-encoded.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathTemplate {
+    raw: String,
+    parts: Vec<Part>,
+}
+
+impl PathTemplate {
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    /* The path, without the query. */
+    pub fn path(&self) -> &str {
+        self.raw.split('?').next().unwrap_or_default()
+    }
+
+    pub fn placeholders(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Placeholder(field) => Some(field.as_str()),
+            Part::Literal(_) => None,
+        })
+    }
+
+    pub fn render(&self, payload: &Value) -> Result<PathAndQuery, MappingError> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Placeholder(field) => {
+                    let value = field
+                        .scalar(payload)?
+                        .ok_or_else(|| MappingError::Missing(field.as_str().to_owned()))?;
+                    rendered.extend(utf8_percent_encode(&value, COMPONENT));
+                }
+            }
+        }
+        // Literals were checked when parsing and values are encoded, so this can't fail.
+        Ok(PathAndQuery::try_from(rendered).expect("rendered path template is a valid path"))
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        if !raw.starts_with('/') {
+            return Err(String::from("path must start with /"));
+        }
+        let mut parts = Vec::new();
+        let mut rest = raw;
+        while let Some(start) = rest.find(['{', '}']) {
+            if rest[start..].starts_with('}') {
+                return Err(format!("unmatched }} in {:?}", raw));
+            }
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("unterminated {{ in {:?}", raw))?;
+            let name = &rest[start + 1..end];
+            let field = FieldPath::parse(name)
+                .map_err(|_err| format!("invalid placeholder {{{}}} in {:?}", name, raw))?;
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            parts.push(Part::Placeholder(field));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+
+        let literals: String = parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.as_str(),
+                Part::Placeholder(_) => "x",
+            })
+            .collect();
+        PathAndQuery::try_from(literals)
+            .map_err(|_err| String::from("Unable to parse path and query"))?;
+
+        Ok(PathTemplate {
+            raw: raw.to_owned(),
+            parts,
+        })
+    }
+}
+
+struct PathTemplateVisitor;
+
+impl<'de> Visitor<'de> for PathTemplateVisitor {
+    type Value = PathTemplate;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("EXPECTED: path and query component, eg: /users/{id}?fields=name")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        PathTemplate::from_str(v).map_err(E::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for PathTemplate {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PathTemplateVisitor)
+    }
+}
+
+impl JsonSchema for PathTemplate {
+    fn schema_name() -> String {
+        String::from("PathTemplate")
+    }
+
+    fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(String::from(
+                    "A path and query, with {placeholders} filled in from the payload",
+                )),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some(String::from("^/")),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+#[test]
+fn render_path_templates() {
+    let template = PathTemplate::from_str("/users/{id}/closets/{closet.id}?v={version}").unwrap();
+    assert_eq!(
+        template.placeholders().collect::<Vec<_>>(),
+        vec!["id", "closet.id", "version"]
+    );
+
+    let payload = serde_json::json!({"id": "a b/c", "closet": {"id": 7}, "version": true});
+    assert_eq!(
+        template.render(&payload).unwrap(),
+        "/users/a%20b%2Fc/closets/7?v=true"
+    );
+    assert_eq!(
+        template.render(&serde_json::json!({"id": 1, "closet": {}})),
+        Err(MappingError::Missing(String::from("closet.id")))
+    );
+    assert_eq!(
+        template.render(&serde_json::json!({"id": [1]})),
+        Err(MappingError::NotScalar(String::from("id")))
+    );
+
+    for invalid in [
+        "users/{id}",
+        "/users/{id",
+        "/users/id}",
+        "/users/{}",
+        "/users/{a b}",
+    ] {
+        assert!(PathTemplate::from_str(invalid).is_err(), "{:?}", invalid);
+    }
+}
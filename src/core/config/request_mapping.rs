@@ -0,0 +1,316 @@
+use std::{collections::BTreeMap, fmt};
+
+use actix_web::http::uri::PathAndQuery;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject, StringValidation},
+    JsonSchema,
+};
+use serde::{
+    de::{value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use serde_json::Value;
+
+use super::path_template::PathTemplate;
+
+/* Everything but RFC 3986's unreserved characters, so a value can't add segments or parameters. */
+pub const COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, PartialEq)]
+pub enum MappingError {
+    Missing(String),
+    NotScalar(String),
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MappingError::Missing(name) => write!(f, "payload has no value for {}", name),
+            MappingError::NotScalar(name) => write!(
+                f,
+                "{} must be a string, number or boolean in the payload",
+                name
+            ),
+        }
+    }
+}
+
+/* FieldPath
+ *
+ * A field of the outgoing payload, with dots reaching into nested objects, eg: "closet.id".
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldPath(String);
+
+impl FieldPath {
+    pub fn parse(raw: &str) -> Result<FieldPath, String> {
+        let valid = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if raw
+            .split('.')
+            .any(|key| key.is_empty() || !key.chars().all(valid))
+        {
+            return Err(format!("invalid field {:?}", raw));
+        }
+        Ok(FieldPath(raw.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /* The field's value, treating null the same as missing. */
+    pub fn get<'a>(&self, payload: &'a Value) -> Option<&'a Value> {
+        self.0
+            .split('.')
+            .try_fold(payload, |value, key| value.get(key))
+            .filter(|value| !value.is_null())
+    }
+
+    /* The field's value as text, for a path, query parameter or header. */
+    pub fn scalar(&self, payload: &Value) -> Result<Option<String>, MappingError> {
+        self.get(payload)
+            .map(|value| scalar(value).ok_or_else(|| MappingError::NotScalar(self.0.clone())))
+            .transpose()
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        Value::Null | Value::Array(_) | Value::Object(_) => None,
+    }
+}
+
+impl<'de> Deserialize<'de> for FieldPath {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        FieldPath::parse(&raw).map_err(serde::de::Error::custom)
+    }
+}
+
+impl JsonSchema for FieldPath {
+    fn schema_name() -> String {
+        String::from("FieldPath")
+    }
+
+    fn json_schema(_gen: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(String::from(
+                    "A field of the payload, with dots reaching into nested objects",
+                )),
+                ..Default::default()
+            })),
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some(String::from(r"^[A-Za-z0-9_-]+(\.[A-Za-z0-9_-]+)*$")),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/* RequestBody
+ *
+ * What is sent as the JSON body: the whole payload (the default), one field of it, or nothing,
+ * eg: `body = "none"` or `body = { field = "data" }`.
+ */
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum RequestBody {
+    #[default]
+    Payload,
+    Empty,
+    Field(FieldPath),
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct BodyField {
+    field: FieldPath,
+}
+
+struct RequestBodyVisitor;
+
+impl<'de> Visitor<'de> for RequestBodyVisitor {
+    type Value = RequestBody;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("EXPECTED: \"payload\", \"none\", or { field = \"...\" }")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        match v {
+            "payload" => Ok(RequestBody::Payload),
+            "none" => Ok(RequestBody::Empty),
+            other => Err(E::invalid_value(serde::de::Unexpected::Str(other), &self)),
+        }
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let BodyField { field } = BodyField::deserialize(MapAccessDeserializer::new(map))?;
+        Ok(RequestBody::Field(field))
+    }
+}
+
+impl<'de> Deserialize<'de> for RequestBody {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(RequestBodyVisitor)
+    }
+}
+
+impl JsonSchema for RequestBody {
+    fn schema_name() -> String {
+        String::from("RequestBody")
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let keyword = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            enum_values: Some(vec![Value::from("payload"), Value::from("none")]),
+            ..Default::default()
+        };
+        SchemaObject {
+            subschemas: Some(Box::new(schemars::schema::SubschemaValidation {
+                any_of: Some(vec![keyword.into(), gen.subschema_for::<BodyField>()]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+pub struct OutgoingRequest {
+    pub path_and_query: PathAndQuery,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Value>,
+}
+
+/* Build a request from the outgoing payload. Query parameters and headers whose field is
+ * missing are left out, and lists become repeated query parameters.
+ */
+pub fn request(
+    path: &PathTemplate,
+    query: &BTreeMap<String, FieldPath>,
+    headers: &BTreeMap<String, FieldPath>,
+    body: &RequestBody,
+    payload: &Value,
+) -> Result<OutgoingRequest, MappingError> {
+    let mut path_and_query = path.render(payload)?.to_string();
+    let mut separator = if path_and_query.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    for (name, field) in query {
+        let values = match field.get(payload) {
+            None => vec![],
+            Some(Value::Array(items)) => items.iter().filter(|item| !item.is_null()).collect(),
+            Some(value) => vec![value],
+        };
+        for value in values {
+            let value = scalar(value).ok_or_else(|| MappingError::NotScalar(name.clone()))?;
+            path_and_query.push(separator);
+            path_and_query.extend(utf8_percent_encode(name, COMPONENT));
+            path_and_query.push('=');
+            path_and_query.extend(utf8_percent_encode(&value, COMPONENT));
+            separator = '&';
+        }
+    }
+
+    let mut mapped_headers = Vec::new();
+    for (name, field) in headers {
+        if let Some(value) = field.scalar(payload)? {
+            mapped_headers.push((name.clone(), value));
+        }
+    }
+
+    let body = match body {
+        RequestBody::Payload => Some(payload.clone()),
+        RequestBody::Empty => None,
+        RequestBody::Field(field) => Some(field.get(payload).cloned().unwrap_or(Value::Null)),
+    };
+
+    Ok(OutgoingRequest {
+        // Names and values are encoded, so this can't fail.
+        path_and_query: PathAndQuery::try_from(path_and_query)
+            .expect("mapped path and query is valid"),
+        headers: mapped_headers,
+        body,
+    })
+}
+
+#[test]
+fn map_requests() {
+    use std::str::FromStr;
+
+    let path = PathTemplate::from_str("/users/{user_id}/lists?v=2").unwrap();
+    let fields = |pairs: &[(&str, &str)]| -> BTreeMap<String, FieldPath> {
+        pairs
+            .iter()
+            .map(|(name, field)| (name.to_string(), FieldPath::parse(field).unwrap()))
+            .collect()
+    };
+    let query = fields(&[
+        ("limit", "page.limit"),
+        ("id", "ids"),
+        ("cursor", "page.cursor"),
+    ]);
+    let headers = fields(&[("x-tenant", "tenant"), ("x-trace", "trace")]);
+    let payload = serde_json::json!({
+        "user_id": 7,
+        "ids": [1, "a&b"],
+        "page": {"limit": 20},
+        "tenant": "acme",
+    });
+
+    let mapped = request(&path, &query, &headers, &RequestBody::Empty, &payload).unwrap();
+    assert_eq!(
+        mapped.path_and_query,
+        "/users/7/lists?v=2&id=1&id=a%26b&limit=20"
+    );
+    assert_eq!(
+        mapped.headers,
+        vec![(String::from("x-tenant"), String::from("acme"))]
+    );
+    assert_eq!(mapped.body, None);
+
+    let body = RequestBody::Field(FieldPath::parse("page").unwrap());
+    let mapped = request(&path, &BTreeMap::new(), &BTreeMap::new(), &body, &payload).unwrap();
+    assert_eq!(mapped.body, Some(serde_json::json!({"limit": 20})));
+
+    let nested = fields(&[("page", "page")]);
+    assert_eq!(
+        request(
+            &path,
+            &nested,
+            &BTreeMap::new(),
+            &RequestBody::Payload,
+            &payload
+        )
+        .err(),
+        Some(MappingError::NotScalar(String::from("page")))
+    );
+}
@@ -0,0 +1,129 @@
+use std::{num::NonZeroU32, time::Duration};
+
+use actix_web::http::StatusCode;
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer};
+
+use super::stringy_duration;
+
+fn default_max_attempts() -> NonZeroU32 {
+    NonZeroU32::new(3).expect("nonzero")
+}
+
+fn default_backoff() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_max_backoff() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_multiplier() -> f64 {
+    2.0
+}
+
+fn default_errors() -> Vec<RetryableError> {
+    vec![RetryableError::Connection, RetryableError::Timeout]
+}
+
+fn default_statuses() -> Vec<StatusCode> {
+    vec![
+        StatusCode::BAD_GATEWAY,
+        StatusCode::SERVICE_UNAVAILABLE,
+        StatusCode::GATEWAY_TIMEOUT,
+    ]
+}
+
+fn status_codes<'de, D>(deserializer: D) -> Result<Vec<StatusCode>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<u16>::deserialize(deserializer)?
+        .into_iter()
+        .map(|code| {
+            StatusCode::from_u16(code).map_err(|_| {
+                serde::de::Error::custom(format!("{} is not an http status code", code))
+            })
+        })
+        .collect()
+}
+
+/* Failures, other than a status code, that are worth another attempt. */
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetryableError {
+    /* The connection couldn't be made, or broke before a response arrived. */
+    Connection,
+    Timeout,
+}
+
+/* How the delay between attempts is randomized, so that callers don't retry in lockstep. */
+#[derive(Clone, Copy, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Jitter {
+    None,
+    /* Anywhere from nothing to the full delay. */
+    #[default]
+    Full,
+    /* At least half the delay. */
+    Equal,
+}
+
+/* RetryPolicy
+ *
+ * How a failed call to a method is attempted again, eg:
+ *
+ *   retry = { max-attempts = 4, backoff = "50ms", statuses = [429, 503] }
+ *
+ * The delay before each retry starts at `backoff` and grows by `multiplier`, up to
+ * `max-backoff`. Only idempotent methods are retried, unless `non-idempotent = true`.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
+pub struct RetryPolicy {
+    #[serde(
+        rename = "max-attempts",
+        alias = "max_attempts",
+        default = "default_max_attempts"
+    )]
+    pub max_attempts: NonZeroU32,
+    #[serde(
+        default = "default_backoff",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub backoff: Duration,
+    #[serde(
+        rename = "max-backoff",
+        alias = "max_backoff",
+        default = "default_max_backoff",
+        deserialize_with = "stringy_duration::deserialize"
+    )]
+    #[schemars(schema_with = "stringy_duration::schema")]
+    pub max_backoff: Duration,
+    #[serde(default = "default_multiplier")]
+    pub multiplier: f64,
+    #[serde(default)]
+    pub jitter: Jitter,
+    #[serde(default = "default_errors")]
+    pub errors: Vec<RetryableError>,
+    #[serde(default = "default_statuses", deserialize_with = "status_codes")]
+    #[schemars(with = "Vec<u16>")]
+    pub statuses: Vec<StatusCode>,
+    #[serde(rename = "non-idempotent", alias = "non_idempotent", default)]
+    pub non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: default_max_attempts(),
+            backoff: default_backoff(),
+            max_backoff: default_max_backoff(),
+            multiplier: default_multiplier(),
+            jitter: Jitter::default(),
+            errors: default_errors(),
+            statuses: default_statuses(),
+            non_idempotent: false,
+        }
+    }
+}
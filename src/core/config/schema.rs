@@ -0,0 +1,73 @@
+use schemars::{
+    schema::{InstanceType, Metadata, ObjectValidation, RootSchema, Schema, SchemaObject},
+    schema_for,
+};
+
+use super::Configuration;
+use crate::model::cryptogram::JsonCryptogram;
+
+/* JSON Schemas for config files, and for the cryptograms accepted by `/evaluate`, so editors
+ * and pre-commit hooks can check them without starting the server. Config files are TOML, but
+ * most tools validate TOML against a JSON Schema just the same.
+ */
+pub fn configuration() -> RootSchema {
+    let mut root = schema_for!(Configuration);
+    // Profiles are merged away before `Configuration` is deserialized, and may override any
+    // part of it, so they're only described loosely.
+    let overlay = SchemaObject {
+        instance_type: Some(InstanceType::Object.into()),
+        ..Default::default()
+    };
+    let profiles = SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(String::from(
+                "Named overlays, deep-merged into the configuration when selected with --profile or DELEGATOR_PROFILE",
+            )),
+            ..Default::default()
+        })),
+        instance_type: Some(InstanceType::Object.into()),
+        object: Some(Box::new(ObjectValidation {
+            additional_properties: Some(Box::new(Schema::Object(overlay))),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    root.schema
+        .object()
+        .properties
+        .insert(String::from("profiles"), profiles.into());
+    root
+}
+
+pub fn cryptogram() -> RootSchema {
+    schema_for!(JsonCryptogram)
+}
+
+#[test]
+fn schema_describes_configuration() {
+    let schema = serde_json::to_value(configuration()).unwrap();
+    let definitions = &schema["definitions"];
+
+    assert_eq!(schema["required"], serde_json::json!(["events", "http"]));
+    assert_eq!(schema["properties"]["profiles"]["type"], "object");
+    assert_eq!(
+        definitions["HttpClientConfig"]["required"],
+        serde_json::json!(["default-timeout", "user-agent"])
+    );
+    assert_eq!(
+        definitions["ServiceDefinition"]["oneOf"][0]["properties"]["protocol"]["enum"],
+        serde_json::json!(["rest"])
+    );
+    assert_eq!(
+        definitions["MethodDefinition"]["properties"]["timeout"]["type"],
+        "string"
+    );
+    assert!(definitions["CorsConfig"]["anyOf"].is_array());
+
+    let schema = serde_json::to_value(cryptogram()).unwrap();
+    assert_eq!(schema["required"], serde_json::json!(["steps"]));
+    assert_eq!(
+        schema["definitions"]["JsonCryptogramStep"]["properties"]["preflight"]["type"],
+        serde_json::json!(["string", "null"])
+    );
+}
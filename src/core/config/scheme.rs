@@ -1,4 +1,8 @@
 use actix_web::http::uri::Scheme;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Schema, SchemaObject},
+};
 use serde::{de::Visitor, Deserializer};
 
 struct ConfigSchemeVisitor;
@@ -28,3 +32,12 @@ where
 {
     de.deserialize_str(ConfigSchemeVisitor)
 }
+
+pub fn schema(_gen: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(vec!["http".into(), "https".into()]),
+        ..Default::default()
+    }
+    .into()
+}
@@ -0,0 +1,198 @@
+use std::{fmt, path::PathBuf};
+
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject, SubschemaValidation},
+    JsonSchema,
+};
+use serde::{
+    de::{value::MapAccessDeserializer, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use serde_json::Value;
+
+pub const REDACTED: &str = "<redacted>";
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Reference {
+    #[serde(skip)]
+    Inline,
+    File(PathBuf),
+    Env(String),
+}
+
+/* Secret
+ *
+ * A string credential, written inline or as a reference to where it lives, eg:
+ * `{ file = "/run/secrets/token" }` or `{ env = "TOKEN" }`. References are resolved when the
+ * config is loaded, and again on every reload. Neither Debug nor Display reveal the value.
+ */
+#[derive(Clone, PartialEq)]
+pub struct Secret {
+    reference: Reference,
+    value: Option<String>,
+}
+
+impl Secret {
+    /* A reference to an environment variable, for defaults like AWS_ACCESS_KEY_ID. */
+    pub fn env(name: &str) -> Secret {
+        Secret {
+            reference: Reference::Env(name.to_owned()),
+            value: None,
+        }
+    }
+
+    /* The value, or None for a reference that hasn't been resolved. */
+    pub fn expose(&self) -> Option<&str> {
+        self.value.as_deref()
+    }
+
+    /* Read the referenced file or variable. Relative paths are relative to `dir`. */
+    pub fn resolve(&mut self, dir: &std::path::Path) -> Result<(), String> {
+        self.value = match &self.reference {
+            Reference::Inline => return Ok(()),
+            Reference::File(path) => {
+                let path = dir.join(path);
+                let contents = std::fs::read_to_string(&path)
+                    .map_err(|err| format!("unable to read {}: {}", path.display(), err))?;
+                Some(contents.trim_end_matches(['\r', '\n']).to_owned())
+            }
+            Reference::Env(name) => Some(
+                std::env::var(name)
+                    .map_err(|_| format!("environment variable {} is not set", name))?,
+            ),
+        };
+        Ok(())
+    }
+
+    /* The file this secret is read from, if any, so it can be watched for changes. */
+    pub fn file(&self) -> Option<&std::path::Path> {
+        match &self.reference {
+            Reference::File(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Secret {
+        Secret {
+            reference: Reference::Inline,
+            value: Some(value),
+        }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.reference {
+            Reference::Inline => write!(f, "{:?}", REDACTED),
+            Reference::File(path) => write!(f, "{{ file = {:?} }}", path),
+            Reference::Env(name) => write!(f, "{{ env = {:?} }}", name),
+        }
+    }
+}
+
+struct SecretVisitor;
+
+impl<'de> Visitor<'de> for SecretVisitor {
+    type Value = Secret;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str(
+            "EXPECTED: a string, or a reference like { file = \"...\" } or { env = \"...\" }",
+        )
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Secret::from(v.to_owned()))
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        Ok(Secret {
+            reference: Reference::deserialize(MapAccessDeserializer::new(map))?,
+            value: None,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(SecretVisitor)
+    }
+}
+
+impl JsonSchema for Secret {
+    fn schema_name() -> String {
+        String::from("Secret")
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            metadata: Some(Box::new(Metadata {
+                description: Some(String::from(
+                    "A string, or a reference to a file or environment variable holding it",
+                )),
+                ..Default::default()
+            })),
+            subschemas: Some(Box::new(SubschemaValidation {
+                any_of: Some(vec![
+                    SchemaObject {
+                        instance_type: Some(InstanceType::String.into()),
+                        ..Default::default()
+                    }
+                    .into(),
+                    gen.subschema_for::<Reference>(),
+                ]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+/* Blank out inline header values in a cryptogram, for printing the effective configuration.
+ * Cryptograms embedded as JSON strings are parsed, and printed as tables instead.
+ */
+pub fn redact_cryptogram(cryptogram: &mut Value) {
+    if let Value::String(embedded) = cryptogram {
+        match serde_json::from_str(embedded) {
+            Ok(parsed) => *cryptogram = parsed,
+            Err(_) => return,
+        }
+    }
+    let steps = cryptogram
+        .get_mut("steps")
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten();
+    for step in steps {
+        let headers = step
+            .get_mut("headers")
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten();
+        for header in headers {
+            if let Some(value @ Value::String(_)) = header.get_mut(1) {
+                *value = Value::from(REDACTED);
+            }
+        }
+    }
+}
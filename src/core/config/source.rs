@@ -0,0 +1,272 @@
+use toml_edit::{ImDocument, Item};
+
+use super::{
+    errors::{KeyPath, Location, Segment},
+    interpolate::Expansions,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /* `.yaml`, `.yml` and `.json` files are YAML and JSON, everything else is TOML. */
+    pub fn from_path(path: &str) -> Format {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    pub fn render(&self, document: &serde_json::Value) -> Result<String, String> {
+        match self {
+            Format::Toml => toml::to_string_pretty(document).map_err(|err| err.to_string()),
+            Format::Yaml => serde_yaml::to_string(document).map_err(|err| err.to_string()),
+            Format::Json => serde_json::to_string_pretty(document).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/* Source
+ *
+ * The (interpolated) text of a config file, kept around after parsing so that errors found
+ * while deserializing or validating can be pointed back at a line and column. Only TOML keeps
+ * track of where each value came from; errors in YAML and JSON files are reported by key path.
+ * Locations are in the file as written, before placeholders were expanded.
+ *
+ * When a profile is selected, `overlay` is where it lives in the document, eg: profiles.production,
+ * and values it overrides are located there rather than in the base configuration.
+ */
+pub struct Source {
+    pub path: String,
+    pub text: String,
+    pub format: Format,
+    pub overlay: Option<KeyPath>,
+    document: Option<ImDocument<String>>,
+    raw: Option<(String, Expansions)>,
+}
+
+impl Source {
+    pub fn new(path: &str, text: String) -> Source {
+        let format = Format::from_path(path);
+        let document = match format {
+            Format::Toml => ImDocument::parse(text.clone()).ok(),
+            Format::Yaml | Format::Json => None,
+        };
+        Source {
+            path: path.to_owned(),
+            text,
+            format,
+            overlay: None,
+            document,
+            raw: None,
+        }
+    }
+
+    /* The text as written, that `text` was expanded from. */
+    pub fn expanded_from(self, raw: String, expansions: Expansions) -> Source {
+        Source {
+            raw: Some((raw, expansions)),
+            ..self
+        }
+    }
+
+    pub fn location_of(&self, offset: usize) -> Location {
+        match &self.raw {
+            Some((raw, expansions)) => Location::from_offset(raw, expansions.raw_offset(offset)),
+            None => Location::from_offset(&self.text, offset),
+        }
+    }
+
+    /* As `location_of`, for a 1-based line and column (in bytes) within `text`. */
+    pub fn location_at(&self, line: usize, column: usize) -> Location {
+        let line_start: usize = self
+            .text
+            .split_inclusive('\n')
+            .take(line.saturating_sub(1))
+            .map(str::len)
+            .sum();
+        self.location_of(line_start + column.saturating_sub(1))
+    }
+
+    /* The span of the deepest value along `key_path`, and how many segments deep it is. */
+    fn span_of(&self, key_path: &KeyPath) -> Option<(usize, std::ops::Range<usize>)> {
+        let mut item: &Item = self.document.as_ref()?.as_item();
+        let mut span = None;
+        for (depth, segment) in key_path.segments().iter().enumerate() {
+            let (key_span, child) = match segment {
+                Segment::Key(key) => {
+                    let Some((key, child)) = item
+                        .as_table_like()
+                        .and_then(|table| table.get_key_value(key))
+                    else {
+                        break;
+                    };
+                    (key.span(), child)
+                }
+                Segment::Index(index) => match item.get(*index) {
+                    Some(child) => (None, child),
+                    None => break,
+                },
+            };
+            if let Some(found) = child.span().or(key_span) {
+                span = Some((depth + 1, found));
+            }
+            item = child;
+        }
+        span
+    }
+
+    /* Prefer the overlay whenever it goes at least as deep as the base configuration. */
+    fn span(&self, key_path: &KeyPath) -> Option<std::ops::Range<usize>> {
+        let base = self.span_of(key_path);
+        let overlaid = self.overlay.as_ref().and_then(|overlay| {
+            let (depth, span) = self.span_of(&overlay.concat(key_path))?;
+            Some((depth.checked_sub(overlay.segments().len())?, span))
+        });
+        match (base, overlaid) {
+            (Some((base_depth, _)), Some((depth, span))) if depth > 0 && depth >= base_depth => {
+                Some(span)
+            }
+            (None, Some((depth, span))) if depth > 0 => Some(span),
+            (base, _) => base.map(|(_, span)| span),
+        }
+    }
+
+    pub fn locate(&self, key_path: &KeyPath) -> Option<Location> {
+        self.span(key_path).map(|span| self.location_of(span.start))
+    }
+
+    /* Locate a position reported by a parser that ran over the decoded contents of a string
+     * value, eg: a serde_json error inside an embedded cryptogram.
+     */
+    pub fn locate_embedded(
+        &self,
+        key_path: &KeyPath,
+        line: usize,
+        column: usize,
+    ) -> Option<Location> {
+        let span = self.span(key_path)?;
+        let raw = &self.text[span.clone()];
+        let offset = embedded_offset(raw, line, column).unwrap_or(0);
+        Some(self.location_of(span.start + offset))
+    }
+}
+
+/* Walk the raw representation of a TOML string, tracking where we are in the decoded value,
+ * until we reach the requested 1-based line and column.
+ */
+fn embedded_offset(raw: &str, line: usize, column: usize) -> Option<usize> {
+    let (mut offset, basic) = if raw.starts_with("\"\"\"") {
+        (3, true)
+    } else if raw.starts_with("'''") {
+        (3, false)
+    } else if raw.starts_with('"') {
+        (1, true)
+    } else if raw.starts_with('\'') {
+        (1, false)
+    } else {
+        return None;
+    };
+    if offset == 3 {
+        if raw[offset..].starts_with("\r\n") {
+            offset += 2;
+        } else if raw[offset..].starts_with('\n') {
+            offset += 1;
+        }
+    }
+
+    let target = column.saturating_sub(1);
+    let (mut current_line, mut current_column) = (1, 0);
+    let mut chars = raw[offset..].char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        if current_line == line && current_column >= target {
+            return Some(offset + idx);
+        }
+        if basic && c == '\\' {
+            match chars.next() {
+                Some((_, 'n')) => {
+                    current_line += 1;
+                    current_column = 0;
+                }
+                Some((_, 'u')) => {
+                    chars.nth(3);
+                    current_column += 1;
+                }
+                Some((_, 'U')) => {
+                    chars.nth(7);
+                    current_column += 1;
+                }
+                Some((_, ws)) if ws.is_whitespace() => {
+                    while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+                }
+                Some(_) => current_column += 1,
+                None => break,
+            }
+        } else if c == '\n' {
+            current_line += 1;
+            current_column = 0;
+        } else {
+            current_column += c.len_utf8();
+        }
+    }
+    None
+}
+
+#[test]
+fn locate_embedded_cryptogram() {
+    let text = String::from(
+        "[virtualhosts.catalog.routes.\"/explore\"]\ncryptogram = \"\"\"\n  {\n    \"steps\": [{\"preflight\": \"{\\\\\"a\\\\\": .}\"} oops]\n  }\n  \"\"\"\n",
+    );
+    let source = Source::new("test.conf", text);
+    let key_path = KeyPath::root()
+        .key("virtualhosts")
+        .key("catalog")
+        .key("routes")
+        .key("/explore")
+        .key("cryptogram");
+
+    assert_eq!(
+        source.locate(&key_path),
+        Some(Location {
+            line: 2,
+            column: 14
+        })
+    );
+
+    // The decoded line is `    "steps": [{"preflight": "{\"a\": ."} oops]`, which serde_json
+    // reports as line 2, column 42. The raw line has two extra backslashes before that point.
+    assert_eq!(
+        source.locate_embedded(&key_path, 2, 42),
+        Some(Location {
+            line: 4,
+            column: 44
+        })
+    );
+}
+
+#[test]
+fn locate_after_expansion() {
+    use super::interpolate::interpolate;
+
+    let raw =
+        "t = { a = \"${DELEGATOR_TEST_UNSET:-a longer default}\", b = 2 }\nc = \"$${\"\nd = 3\n";
+    let (text, expansions) = interpolate(raw, Format::Toml).unwrap();
+    let source = Source::new("test.conf", text).expanded_from(raw.to_owned(), expansions);
+
+    let b = KeyPath::root().key("t").key("b");
+    let column = raw.find("2 }").unwrap() + 1;
+    assert_eq!(source.locate(&b), Some(Location { line: 1, column }));
+    assert_eq!(
+        source.locate(&KeyPath::root().key("d")),
+        Some(Location { line: 3, column: 5 })
+    );
+}
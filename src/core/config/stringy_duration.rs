@@ -1,8 +1,64 @@
 use std::time::Duration;
 
-use nom::{bytes::streaming::tag, number::complete::float, IResult};
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, digit1},
+    combinator::{all_consuming, map_res, opt, recognize, value},
+    multi::many1,
+    sequence::pair,
+    IResult,
+};
 
-use serde::{de::Visitor, Deserializer};
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject, StringValidation},
+};
+use serde::{
+    de::{Unexpected, Visitor},
+    Deserializer,
+};
+
+/* Durations
+ *
+ * One or more `<number><unit>` pairs, where the unit is one of `ms`, `s`, `m` or `h`, eg: "250ms",
+ * "1.5s", "1m30s", "2h". Each unit may appear once, largest first, so "30s1m" and "1s1s" are
+ * rejected rather than added up. ISO 8601 durations are accepted too, eg: "PT30S" or "PT1M30S".
+ */
+fn number(input: &str) -> IResult<&str, f64> {
+    map_res(
+        recognize(pair(digit1, opt(pair(char('.'), digit1)))),
+        str::parse,
+    )(input)
+}
+
+fn unit(input: &str) -> IResult<&str, f64> {
+    alt((
+        value(0.001, tag("ms")),
+        value(1.0, tag("s")),
+        value(60.0, tag("m")),
+        value(3600.0, tag("h")),
+    ))(input)
+}
+
+fn compound(input: &str) -> IResult<&str, Vec<(f64, f64)>> {
+    all_consuming(many1(pair(number, unit)))(input)
+}
+
+pub fn parse(input: &str) -> Option<Duration> {
+    if input.starts_with('P') {
+        return match iso8601::parsers::parse_duration(input.as_bytes()) {
+            Ok((b"", duration)) => Some(duration.into()),
+            _ => None,
+        };
+    }
+    let (_, parts) = compound(input).ok()?;
+    if !parts.windows(2).all(|pair| pair[0].1 > pair[1].1) {
+        return None;
+    }
+    let secs = parts.iter().map(|(amount, unit)| amount * unit).sum();
+    Duration::try_from_secs_f64(secs).ok()
+}
 
 struct StringyDuration;
 
@@ -10,22 +66,16 @@ impl<'de> Visitor<'de> for StringyDuration {
     type Value = Duration;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("EXPECTED: path and query component, eg: /foo?bar=baz")
+        formatter.write_str(
+            "EXPECTED: a duration with units ms, s, m or h, eg: \"1m30s\", or an ISO 8601 duration, eg: \"PT1M30S\"",
+        )
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        let parse_res: IResult<&str, f32> = nom::sequence::terminated(float, tag("s"))(v);
-        let (_, secs) = parse_res
-            .map_err(|e| E::custom(format!("Unable to parse stringy duration: {:?}", e)))?;
-        //     separated_list1(delimited(space0, tag(","), space0), parse_thunk)(v)?;
-        // match matched.as_slice() {
-        //     [only] => Ok((input, only.clone())),
-        //     rest => Ok((input, Language::Splat(rest.to_vec()))),
-        // }
-        Ok(Duration::from_secs_f32(secs))
+        parse(v).ok_or_else(|| E::invalid_value(Unexpected::Str(v), &self))
     }
 }
 
@@ -35,3 +85,71 @@ where
 {
     de.deserialize_str(StringyDuration)
 }
+
+pub fn schema(_gen: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        metadata: Some(Box::new(Metadata {
+            description: Some(String::from(
+                "A duration with units ms, s, m or h, eg: \"1m30s\", or an ISO 8601 duration, eg: \"PT1M30S\"",
+            )),
+            ..Default::default()
+        })),
+        instance_type: Some(InstanceType::String.into()),
+        string: Some(Box::new(StringValidation {
+            pattern: Some(String::from(
+                r"^((?=[0-9])([0-9]+(\.[0-9]+)?h)?([0-9]+(\.[0-9]+)?m)?([0-9]+(\.[0-9]+)?s)?([0-9]+(\.[0-9]+)?ms)?|P.+)$",
+            )),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
+/* For optional fields, alongside `#[serde(default)]`. */
+pub mod option {
+    use std::time::Duration;
+
+    use schemars::{gen::SchemaGenerator, schema::Schema};
+    use serde::Deserializer;
+
+    pub fn deserialize<'de, D>(de: D) -> Result<Option<Duration>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        super::deserialize(de).map(Some)
+    }
+
+    pub fn schema(gen: &mut SchemaGenerator) -> Schema {
+        super::schema(gen)
+    }
+}
+
+#[test]
+fn parse_stringy_durations() {
+    assert_eq!(parse("30s"), Some(Duration::from_secs(30)));
+    assert_eq!(parse("1.5s"), Some(Duration::from_millis(1500)));
+    assert_eq!(parse("250ms"), Some(Duration::from_millis(250)));
+    assert_eq!(parse("10m"), Some(Duration::from_secs(600)));
+    assert_eq!(parse("2h"), Some(Duration::from_secs(7200)));
+    assert_eq!(parse("1m30s"), Some(Duration::from_secs(90)));
+    assert_eq!(parse("1h1m1s500ms"), Some(Duration::from_millis(3_661_500)));
+    assert_eq!(parse("PT30S"), Some(Duration::from_secs(30)));
+    assert_eq!(parse("PT1M30S"), Some(Duration::from_secs(90)));
+
+    for invalid in [
+        "", "30", "s", "30x", "1m 30s", "-5s", "inf s", "P", "PT30Sx", "1s1s", "30s1m", "1m1h",
+        "500ms1s", "1h2h",
+    ] {
+        assert_eq!(parse(invalid), None, "{:?}", invalid);
+    }
+
+    let err = serde_json::from_value::<crate::config::HttpClientConfig>(serde_json::json!({
+        "user-agent": "delegator",
+        "default-timeout": "30 seconds",
+    }))
+    .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("invalid value: string \"30 seconds\""));
+}
@@ -0,0 +1,175 @@
+use std::num::NonZeroU32;
+
+use actix_web::http::uri::Authority;
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{ArrayValidation, InstanceType, Schema, SchemaObject, SubschemaValidation},
+    JsonSchema,
+};
+use serde::{
+    de::{value::MapAccessDeserializer, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+
+use super::request_mapping::FieldPath;
+
+/* Each unit of weight is a share of the consistent-hash ring, so weights are kept small. */
+pub const MAX_WEIGHT: u32 = 1000;
+
+fn default_weight() -> NonZeroU32 {
+    NonZeroU32::MIN
+}
+
+/* Upstream
+ *
+ * One of the authorities serving a service, eg: "catalog-1:8080", or
+ * `{ authority = "catalog-2:8080", weight = 3 }` to take a larger share with the weighted and
+ * consistent-hash strategies. Weights go up to `MAX_WEIGHT`.
+ */
+#[derive(Clone, Debug, Deserialize, JsonSchema, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    #[serde(with = "http_serde::authority")]
+    #[schemars(with = "String")]
+    pub authority: Authority,
+    #[serde(default = "default_weight")]
+    pub weight: NonZeroU32,
+}
+
+impl From<Authority> for Upstream {
+    fn from(authority: Authority) -> Upstream {
+        Upstream {
+            authority,
+            weight: default_weight(),
+        }
+    }
+}
+
+/* Balance
+ *
+ * How calls are spread over a service's authorities, eg: `balance = "least-in-flight"`, or
+ * `balance = { consistent-hash = "user_id" }` to send calls with the same value of a payload
+ * field to the same authority.
+ */
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    Random,
+    Weighted,
+    LeastInFlight,
+    ConsistentHash(FieldPath),
+}
+
+struct UpstreamVisitor;
+
+impl<'de> Visitor<'de> for UpstreamVisitor {
+    type Value = Upstream;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("EXPECTED: an authority, or { authority = \"...\", weight = ... }")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        v.parse::<Authority>()
+            .map(Upstream::from)
+            .map_err(|err| E::custom(format!("invalid authority {:?}: {}", v, err)))
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        Upstream::deserialize(MapAccessDeserializer::new(map))
+    }
+}
+
+struct UpstreamEntry(Upstream);
+
+impl<'de> Deserialize<'de> for UpstreamEntry {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer
+            .deserialize_any(UpstreamVisitor)
+            .map(UpstreamEntry)
+    }
+}
+
+struct UpstreamsVisitor;
+
+impl<'de> Visitor<'de> for UpstreamsVisitor {
+    type Value = Vec<Upstream>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("EXPECTED: an authority, or a list of them")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        UpstreamVisitor.visit_str(v).map(|upstream| vec![upstream])
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut upstreams = Vec::new();
+        while let Some(UpstreamEntry(upstream)) = seq.next_element()? {
+            upstreams.push(upstream);
+        }
+        if upstreams.is_empty() {
+            return Err(serde::de::Error::invalid_length(0, &self));
+        }
+        Ok(upstreams)
+    }
+}
+
+/* A service's `authority`, either one or a list of them. */
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Upstream>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_any(UpstreamsVisitor)
+}
+
+pub fn schema(gen: &mut SchemaGenerator) -> Schema {
+    let authority = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        ..Default::default()
+    };
+    let entry = SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![
+                authority.clone().into(),
+                gen.subschema_for::<Upstream>(),
+            ]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    let list = SchemaObject {
+        instance_type: Some(InstanceType::Array.into()),
+        array: Some(Box::new(ArrayValidation {
+            items: Some(Schema::from(entry).into()),
+            min_items: Some(1),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    SchemaObject {
+        subschemas: Some(Box::new(SubschemaValidation {
+            any_of: Some(vec![authority.into(), list.into()]),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
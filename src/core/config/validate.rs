@@ -0,0 +1,728 @@
+use std::str::FromStr;
+
+use actix_web::{dev::ResourceDef, http::header::HeaderName};
+
+use super::{
+    auth::AuthConfig, errors::KeyPath, retry::RetryPolicy, upstream::MAX_WEIGHT, Configuration,
+    HttpConfig, ServiceDefinition, Services, Virtualhosts,
+};
+use crate::model::cryptogram::JsonCryptogram;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    pub key_path: KeyPath,
+    pub message: String,
+}
+
+/* Delays between retries must never shrink, and start no longer than they're allowed to get. */
+fn retry_policy(
+    policy: &RetryPolicy,
+    key_path: &KeyPath,
+    issue: &mut impl FnMut(Severity, KeyPath, String),
+) {
+    if !(1.0..).contains(&policy.multiplier) {
+        issue(
+            Severity::Error,
+            key_path.key("multiplier"),
+            String::from("multiplier must be at least 1"),
+        );
+    }
+    if policy.backoff > policy.max_backoff {
+        issue(
+            Severity::Error,
+            key_path.key("backoff"),
+            format!(
+                "backoff can be at most the max-backoff of {:?}",
+                policy.max_backoff
+            ),
+        );
+    }
+}
+
+/* Static checks for cryptograms
+ *
+ * Everything here would otherwise only surface once a request hits the route: unknown services
+ * and methods are hard errors, suspicious-but-legal constructions are warnings.
+ */
+pub fn cryptogram(
+    cryptogram: &JsonCryptogram,
+    services: &Services,
+    key_path: &KeyPath,
+) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut issue = |severity: Severity, key_path: KeyPath, message: String| {
+        issues.push(Issue {
+            severity,
+            key_path,
+            message,
+        })
+    };
+
+    if cryptogram.steps.is_empty() {
+        issue(
+            Severity::Error,
+            key_path.clone(),
+            String::from("cryptogram has no steps"),
+        );
+    }
+
+    for (idx, step) in cryptogram.steps.iter().enumerate() {
+        let step_path = key_path.key("steps").index(idx);
+
+        match (&step.service, &step.method) {
+            (Some(service_name), Some(method_name)) => match services.get(service_name) {
+                None => issue(
+                    Severity::Error,
+                    step_path.key("service"),
+                    format!("unknown service {:?}", service_name),
+                ),
+                Some(ServiceDefinition::Rest {
+                    methods,
+                    virtualhosts,
+                    ..
+                }) => {
+                    if !methods.contains_key(method_name) {
+                        issue(
+                            Severity::Error,
+                            step_path.key("method"),
+                            format!(
+                                "unknown method {:?} for service {:?}",
+                                method_name, service_name
+                            ),
+                        );
+                    }
+                    if virtualhosts.is_some() && step.headers.is_some() {
+                        issue(
+                            Severity::Error,
+                            step_path.key("headers"),
+                            format!(
+                                "service {:?} is a loopback, its routes only see the body",
+                                service_name
+                            ),
+                        );
+                    }
+                }
+            },
+            (Some(_), None) => issue(
+                Severity::Error,
+                step_path.clone(),
+                String::from("step names a service but no method"),
+            ),
+            (None, Some(_)) => issue(
+                Severity::Error,
+                step_path.clone(),
+                String::from("step names a method but no service"),
+            ),
+            (None, None) => {
+                if step.postflight.is_some() {
+                    issue(
+                        Severity::Warning,
+                        step_path.key("postflight"),
+                        String::from("postflight without a service runs against the payload, like a preflight"),
+                    );
+                }
+            }
+        }
+
+        if let Some(policy) = &step.retry {
+            retry_policy(policy, &step_path.key("retry"), &mut issue);
+        }
+
+        if idx > 0 && step.payload.is_some() {
+            issue(
+                Severity::Warning,
+                step_path.key("payload"),
+                format!(
+                    "payload will be discarded, replaced by the result of step {}",
+                    idx - 1
+                ),
+            );
+        }
+    }
+
+    issues
+}
+
+pub fn listeners(http: &HttpConfig, virtualhosts: &Virtualhosts) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut error = |key_path: KeyPath, message: String| {
+        issues.push(Issue {
+            severity: Severity::Error,
+            key_path,
+            message,
+        })
+    };
+    let http_path = KeyPath::root().key("http");
+
+    if http.listeners.is_empty() {
+        if http.host.is_none() || http.port.is_none() {
+            error(
+                http_path,
+                String::from("expected `host` and `port`, or `listeners`"),
+            );
+        }
+        return issues;
+    }
+    if http.host.is_some() || http.port.is_some() || http.tls.is_some() {
+        error(
+            http_path.clone(),
+            String::from("`host`, `port` and `tls` can't be combined with `listeners`"),
+        );
+    }
+
+    for (idx, listener) in http.listeners.iter().enumerate() {
+        let listener_path = http_path.key("listeners").index(idx);
+        match (&listener.socket, &listener.host, listener.port) {
+            (Some(_), None, None) => {
+                if listener.tls.is_some() {
+                    error(
+                        listener_path.key("tls"),
+                        String::from("TLS is only supported on TCP listeners"),
+                    );
+                }
+            }
+            (Some(_), _, _) => error(
+                listener_path.clone(),
+                String::from("listener has both a `socket` and a `host` or `port`"),
+            ),
+            (None, Some(_), Some(_)) => {}
+            (None, _, _) => error(
+                listener_path.clone(),
+                String::from("expected `host` and `port`, or `socket`"),
+            ),
+        }
+        for (name_idx, name) in listener.virtualhosts.iter().flatten().enumerate() {
+            if !virtualhosts.contains_key(name) {
+                error(
+                    listener_path.key("virtualhosts").index(name_idx),
+                    format!("unknown virtualhost {:?}", name),
+                );
+            }
+        }
+    }
+    issues
+}
+
+/* Paths answered by listeners that serve `/evaluate`, ahead of any virtualhost's routes. */
+const RESERVED: [&str; 2] = ["/evaluate", "/upstreams"];
+
+/* A route for one of those paths would never be reached on such a listener. Routes with dynamic
+ * segments that happen to match one still serve every other path, so they're left alone.
+ */
+pub fn reserved_routes(http: &HttpConfig, virtualhosts: &Virtualhosts) -> Vec<Issue> {
+    let listeners = http.listeners();
+    let mut issues = Vec::new();
+    for (vhost_name, vhost) in virtualhosts {
+        let shadowed = listeners
+            .iter()
+            .any(|listener| listener.evaluate && listener.serves(vhost_name));
+        if !shadowed {
+            continue;
+        }
+        for route in vhost.routes.keys() {
+            if let Some(path) = RESERVED.iter().find(|path| *path == route) {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    key_path: KeyPath::root()
+                        .key("virtualhosts")
+                        .key(vhost_name)
+                        .key("routes")
+                        .key(route),
+                    message: format!(
+                        "{} is served by listeners with `evaluate`, set `evaluate = false` on those serving this virtualhost",
+                        path
+                    ),
+                });
+            }
+        }
+    }
+    issues
+}
+
+/* Services that list `virtualhosts` are dispatched into our own routes, so each of their methods
+ * needs a route to land on. Routes only see the request body, so query and header mappings
+ * would go nowhere.
+ */
+pub fn loopback(services: &Services, virtualhosts: &Virtualhosts) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for (service_name, service) in services {
+        let ServiceDefinition::Rest {
+            methods,
+            virtualhosts: Some(names),
+            ..
+        } = service
+        else {
+            continue;
+        };
+        let service_path = KeyPath::root().key("services").key(service_name);
+        for (idx, name) in names.iter().enumerate() {
+            if !virtualhosts.contains_key(name) {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    key_path: service_path.key("virtualhosts").index(idx),
+                    message: format!("unknown virtualhost {:?}", name),
+                });
+            }
+        }
+        let routes: Vec<ResourceDef> = names
+            .iter()
+            .filter_map(|name| virtualhosts.get(name))
+            .flat_map(|vhost| vhost.routes.keys())
+            .map(|route| ResourceDef::new(route.as_str()))
+            .collect();
+        for (method_name, method) in methods {
+            let method_path = service_path.key("methods").key(method_name);
+            for (key, mapped) in [("query", &method.query), ("headers", &method.headers)] {
+                if !mapped.is_empty() {
+                    issues.push(Issue {
+                        severity: Severity::Error,
+                        key_path: method_path.key(key),
+                        message: format!(
+                            "loopback calls only pass the body to the route, not {}",
+                            key
+                        ),
+                    });
+                }
+            }
+            if !routes
+                .iter()
+                .any(|route| route.is_match(method.path.path()))
+            {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    key_path: method_path.key("path"),
+                    message: format!(
+                        "no route in virtualhosts {:?} matches {:?}",
+                        names,
+                        method.path.path()
+                    ),
+                });
+            }
+        }
+    }
+    issues
+}
+
+/* An API key goes in exactly one place, a header or a query parameter, and tokens are fetched from
+ * an absolute url.
+ */
+pub fn auth(services: &Services) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for (service_name, service) in services {
+        let ServiceDefinition::Rest {
+            auth: Some(auth), ..
+        } = service
+        else {
+            continue;
+        };
+        let key_path = KeyPath::root()
+            .key("services")
+            .key(service_name)
+            .key("auth");
+        match auth {
+            AuthConfig::ApiKey { header, query, .. } if header.is_some() == query.is_some() => {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    key_path,
+                    message: String::from("api-key auth needs exactly one of `header` or `query`"),
+                });
+            }
+            AuthConfig::OAuth2(config) if config.token_url.authority().is_none() => {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    key_path: key_path.key("token-url"),
+                    message: String::from("token-url must be an absolute url"),
+                });
+            }
+            _ => {}
+        }
+    }
+    issues
+}
+
+/* A circuit breaker's failure rate is a fraction of calls, and it can't wait for more calls than
+ * its window holds.
+ */
+pub fn circuit_breakers(services: &Services) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for (service_name, service) in services {
+        let ServiceDefinition::Rest {
+            circuit_breaker: Some(breaker),
+            ..
+        } = service
+        else {
+            continue;
+        };
+        let key_path = KeyPath::root()
+            .key("services")
+            .key(service_name)
+            .key("circuit-breaker");
+        if !(breaker.failure_rate > 0.0 && breaker.failure_rate <= 1.0) {
+            issues.push(Issue {
+                severity: Severity::Error,
+                key_path: key_path.key("failure-rate"),
+                message: String::from("failure-rate must be more than 0 and at most 1"),
+            });
+        }
+        if breaker.minimum_calls > breaker.window {
+            issues.push(Issue {
+                severity: Severity::Error,
+                key_path: key_path.key("minimum-calls"),
+                message: format!(
+                    "minimum-calls can be at most the window of {} calls",
+                    breaker.window
+                ),
+            });
+        }
+    }
+    issues
+}
+
+/* Browsers send cookies along with credentialed CORS requests, so allowing them from every
+ * origin would let any site make requests as the user.
+ */
+pub fn cors(http: &HttpConfig, virtualhosts: &Virtualhosts) -> Vec<Issue> {
+    let mut policies = vec![(KeyPath::root().key("http").key("cors"), &http.cors)];
+    for (vhost_name, vhost) in virtualhosts {
+        if let Some(cors) = &vhost.cors {
+            let key_path = KeyPath::root()
+                .key("virtualhosts")
+                .key(vhost_name)
+                .key("cors");
+            policies.push((key_path, cors));
+        }
+    }
+
+    let mut issues = Vec::new();
+    for (key_path, cors) in policies {
+        if cors.credentials && cors.allows_any_origin() {
+            issues.push(Issue {
+                severity: Severity::Error,
+                key_path: key_path.key("origins"),
+                message: String::from(
+                    "origin \"*\" can't be combined with `credentials`, list the origins instead",
+                ),
+            });
+        }
+    }
+    issues
+}
+
+pub fn retries(services: &Services) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut issue = |severity: Severity, key_path: KeyPath, message: String| {
+        issues.push(Issue {
+            severity,
+            key_path,
+            message,
+        })
+    };
+    for (service_name, service) in services {
+        let ServiceDefinition::Rest { methods, .. } = service;
+        for (method_name, method) in methods {
+            if let Some(policy) = &method.retry {
+                let key_path = KeyPath::root()
+                    .key("services")
+                    .key(service_name)
+                    .key("methods")
+                    .key(method_name)
+                    .key("retry");
+                retry_policy(policy, &key_path, &mut issue);
+            }
+        }
+    }
+    issues
+}
+
+pub fn upstreams(services: &Services) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for (service_name, service) in services {
+        let ServiceDefinition::Rest { authorities, .. } = service;
+        for (idx, upstream) in authorities.iter().enumerate() {
+            if upstream.weight.get() > MAX_WEIGHT {
+                issues.push(Issue {
+                    severity: Severity::Error,
+                    key_path: KeyPath::root()
+                        .key("services")
+                        .key(service_name)
+                        .key("authority")
+                        .index(idx)
+                        .key("weight"),
+                    message: format!("weight can be at most {}", MAX_WEIGHT),
+                });
+            }
+        }
+    }
+    issues
+}
+
+/* Header names are checked up front, rather than failing every request that would send them. */
+pub fn headers(services: &Services) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    for (service_name, service) in services {
+        let ServiceDefinition::Rest { methods, .. } = service;
+        for (method_name, method) in methods {
+            for name in method.headers.keys() {
+                if HeaderName::from_str(name).is_err() {
+                    issues.push(Issue {
+                        severity: Severity::Error,
+                        key_path: KeyPath::root()
+                            .key("services")
+                            .key(service_name)
+                            .key("methods")
+                            .key(method_name)
+                            .key("headers")
+                            .key(name),
+                        message: format!("{:?} is not a valid header name", name),
+                    });
+                }
+            }
+        }
+    }
+    issues
+}
+
+pub fn configuration(config: &Configuration) -> Vec<Issue> {
+    let mut issues = listeners(&config.http, &config.virtualhosts);
+    issues.extend(cors(&config.http, &config.virtualhosts));
+    issues.extend(reserved_routes(&config.http, &config.virtualhosts));
+    issues.extend(loopback(&config.services, &config.virtualhosts));
+    issues.extend(auth(&config.services));
+    issues.extend(headers(&config.services));
+    issues.extend(upstreams(&config.services));
+    issues.extend(retries(&config.services));
+    issues.extend(circuit_breakers(&config.services));
+    let listeners = config.http.listeners();
+    for (vhost_name, vhost) in &config.virtualhosts {
+        let served_with_tls = listeners
+            .iter()
+            .any(|listener| listener.tls.is_some() && listener.serves(vhost_name));
+        if vhost.tls.is_some() && !served_with_tls {
+            issues.push(Issue {
+                severity: Severity::Warning,
+                key_path: KeyPath::root()
+                    .key("virtualhosts")
+                    .key(vhost_name)
+                    .key("tls"),
+                message: String::from(
+                    "certificate is unused, no TLS listener serves this virtualhost",
+                ),
+            });
+        }
+        for (route, edge_route) in &vhost.routes {
+            let key_path = KeyPath::root()
+                .key("virtualhosts")
+                .key(vhost_name)
+                .key("routes")
+                .key(route)
+                .key("cryptogram");
+            issues.extend(cryptogram(
+                &edge_route.cryptogram,
+                &config.services,
+                &key_path,
+            ));
+        }
+    }
+    issues
+}
+
+#[test]
+fn validate_cryptogram() {
+    let services: Services = toml::from_str(
+        r#"
+        [catalog]
+        protocol = "rest"
+        scheme = "http"
+        authority = ["localhost:8080", { authority = "localhost:8081", weight = 1000000 }]
+
+        [catalog.methods.lookup]
+        path = "/lookup/"
+        method = "POST"
+        headers = { x-tenant = "tenant", "x trace" = "trace" }
+
+        [internal]
+        protocol = "rest"
+        scheme = "http"
+        authority = "localhost:8080"
+        virtualhosts = ["internal"]
+        methods.quote = { path = "/quote/", method = "POST", query = { id = "id" }, retry = { multiplier = 0.5 } }
+        "#,
+    )
+    .unwrap();
+    let program = JsonCryptogram::from_str(
+        r#"{"steps": [
+            {"payload": {"ids": [1, 2]}},
+            {"service": "catalog", "method": "lookup", "payload": {"ids": [3]}},
+            {"service": "catalog", "method": "search"},
+            {"service": "pricing", "method": "lookup"},
+            {"service": "catalog"},
+            {"postflight": "."},
+            {"service": "internal", "method": "quote", "headers": [["x-trace", "1"]]},
+            {"service": "catalog", "method": "lookup", "retry": {"multiplier": 0.5, "backoff": "5s"}}
+        ]}"#,
+    )
+    .unwrap();
+
+    let issues: Vec<(Severity, String)> = cryptogram(&program, &services, &KeyPath::root())
+        .into_iter()
+        .map(|issue| (issue.severity, issue.key_path.to_string()))
+        .collect();
+    assert_eq!(
+        issues,
+        vec![
+            (Severity::Warning, String::from("steps[1].payload")),
+            (Severity::Error, String::from("steps[2].method")),
+            (Severity::Error, String::from("steps[3].service")),
+            (Severity::Error, String::from("steps[4]")),
+            (Severity::Warning, String::from("steps[5].postflight")),
+            (Severity::Error, String::from("steps[6].headers")),
+            (Severity::Error, String::from("steps[7].retry.multiplier")),
+            (Severity::Error, String::from("steps[7].retry.backoff")),
+        ]
+    );
+    let issues: Vec<String> = retries(&services)
+        .into_iter()
+        .map(|issue| issue.key_path.to_string())
+        .collect();
+    assert_eq!(
+        issues,
+        vec!["services.internal.methods.quote.retry.multiplier"]
+    );
+    let issues: Vec<String> = headers(&services)
+        .into_iter()
+        .map(|issue| issue.key_path.to_string())
+        .collect();
+    assert_eq!(
+        issues,
+        vec!["services.catalog.methods.lookup.headers.\"x trace\""]
+    );
+    let issues: Vec<String> = upstreams(&services)
+        .into_iter()
+        .map(|issue| issue.key_path.to_string())
+        .collect();
+    assert_eq!(issues, vec!["services.catalog.authority[1].weight"]);
+
+    // Loopback routes only see the body, so query and header mappings can't reach them.
+    let virtualhosts: Virtualhosts = toml::from_str(
+        r#"
+        [internal]
+        hostname = "localhost"
+        routes = {}
+        "#,
+    )
+    .unwrap();
+    let issues: Vec<String> = loopback(&services, &virtualhosts)
+        .into_iter()
+        .map(|issue| issue.key_path.to_string())
+        .collect();
+    assert_eq!(
+        issues,
+        vec![
+            "services.internal.methods.quote.query",
+            "services.internal.methods.quote.path",
+        ]
+    );
+
+    let empty = JsonCryptogram::from_str(r#"{"steps": []}"#).unwrap();
+    assert_eq!(cryptogram(&empty, &services, &KeyPath::root()).len(), 1);
+}
+
+#[test]
+fn validate_listeners() {
+    let virtualhosts: Virtualhosts = toml::from_str(
+        r#"
+        [public]
+        hostname = "example.com"
+        routes = {}
+
+        [internal]
+        hostname = "internal.example.com"
+        routes."/upstreams".cryptogram = '{"steps": [{"payload": 1}]}'
+        routes."/{section}".cryptogram = '{"steps": [{"payload": 1}]}'
+        "#,
+    )
+    .unwrap();
+    let http: HttpConfig = toml::from_str(
+        r#"
+        port = 8080
+        client = { user-agent = "delegator/0.1.0", default-timeout = "30s" }
+
+        [[listeners]]
+        host = "0.0.0.0"
+        port = 443
+        virtualhosts = ["public", "private"]
+        evaluate = false
+
+        [[listeners]]
+        socket = "/run/delegator.sock"
+        tls = { certificate = "cert.pem", key = "key.pem" }
+
+        [[listeners]]
+        host = "127.0.0.1"
+        "#,
+    )
+    .unwrap();
+
+    let issues: Vec<String> = listeners(&http, &virtualhosts)
+        .into_iter()
+        .map(|issue| issue.key_path.to_string())
+        .collect();
+    assert_eq!(
+        issues,
+        vec![
+            "http",
+            "http.listeners[0].virtualhosts[1]",
+            "http.listeners[1].tls",
+            "http.listeners[2]",
+        ]
+    );
+    // The first listener doesn't serve `/evaluate` and `/upstreams`, but the others serve them
+    // to every virtualhost.
+    let issues: Vec<String> = reserved_routes(&http, &virtualhosts)
+        .into_iter()
+        .map(|issue| issue.key_path.to_string())
+        .collect();
+    assert_eq!(issues, vec!["virtualhosts.internal.routes.\"/upstreams\""]);
+    assert!(http.listeners[0].serves("public"));
+    assert!(!http.listeners[0].serves("internal"));
+    assert!(http.listeners[1].serves("internal"));
+}
+
+#[test]
+fn validate_cors() {
+    let http: HttpConfig = toml::from_str(
+        r#"
+        port = 8080
+        cors = { origins = ["*"], credentials = true }
+        client = { user-agent = "delegator/0.1.0", default-timeout = "30s" }
+        "#,
+    )
+    .unwrap();
+    let virtualhosts: Virtualhosts = toml::from_str(
+        r#"
+        [public]
+        hostname = "example.com"
+        routes = {}
+        cors = ["*"]
+
+        [internal]
+        hostname = "internal.example.com"
+        routes = {}
+        cors = { origins = ["https://*.corp.example.com"], credentials = true }
+        "#,
+    )
+    .unwrap();
+
+    let issues: Vec<String> = cors(&http, &virtualhosts)
+        .into_iter()
+        .map(|issue| issue.key_path.to_string())
+        .collect();
+    assert_eq!(issues, vec!["http.cors.origins"]);
+}
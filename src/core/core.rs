@@ -1,5 +1,12 @@
+pub mod auth;
+pub mod bulkhead;
 pub mod cache;
+pub mod circuit_breaker;
 pub mod config;
 pub mod events;
 pub mod model;
+pub mod reload;
+pub mod retry;
 pub mod routes;
+pub mod tls;
+pub mod upstream;
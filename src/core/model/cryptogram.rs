@@ -1,10 +1,12 @@
+use schemars::JsonSchema;
 use serde::Deserialize;
 use serde_json::Value;
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
+use crate::config::{retry::RetryPolicy, secret::Secret};
 use json_adapter::language::Language;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, JsonSchema)]
 pub struct JsonCryptogram {
     pub steps: Vec<JsonCryptogramStep>,
 }
@@ -17,15 +19,24 @@ impl FromStr for JsonCryptogram {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, JsonSchema)]
 pub struct JsonCryptogramStep {
     pub service: Option<String>,
     pub method: Option<String>,
     pub payload: Option<Value>,
+    #[schemars(with = "Option<String>")]
     pub preflight: Option<Language>,
+    #[schemars(with = "Option<String>")]
     pub postflight: Option<Language>,
     pub memoization_prefix: Option<String>,
-    pub headers: Option<Vec<(String, String)>>,
+    pub headers: Option<Vec<(String, Secret)>>,
+    #[serde(
+        default,
+        deserialize_with = "crate::config::stringy_duration::option::deserialize"
+    )]
+    #[schemars(schema_with = "crate::config::stringy_duration::option::schema")]
+    pub timeout: Option<Duration>,
+    pub retry: Option<RetryPolicy>,
 }
 
 impl JsonCryptogramStep {
@@ -53,6 +64,8 @@ impl JsonCryptogramStepNeedsPayload {
                 postflight: None,
                 memoization_prefix: None,
                 headers: None,
+                timeout: None,
+                retry: None,
             },
         }
     }
@@ -92,7 +105,7 @@ impl JsonCryptogramStepBuilder {
 
     pub fn header(self, key: String, value: String) -> JsonCryptogramStepBuilder {
         let mut headers = self.inner.headers.unwrap_or_default();
-        headers.push((key, value));
+        headers.push((key, Secret::from(value)));
         JsonCryptogramStepBuilder {
             inner: JsonCryptogramStep {
                 headers: Some(headers),
@@ -103,8 +116,8 @@ impl JsonCryptogramStepBuilder {
 
     pub fn headers(self, pairs: Vec<(String, String)>) -> JsonCryptogramStepBuilder {
         let mut headers = self.inner.headers.unwrap_or_default();
-        for pair in pairs {
-            headers.push(pair);
+        for (key, value) in pairs {
+            headers.push((key, Secret::from(value)));
         }
         JsonCryptogramStepBuilder {
             inner: JsonCryptogramStep {
@@ -114,6 +127,24 @@ impl JsonCryptogramStepBuilder {
         }
     }
 
+    pub fn timeout(self, timeout: Duration) -> JsonCryptogramStepBuilder {
+        JsonCryptogramStepBuilder {
+            inner: JsonCryptogramStep {
+                timeout: Some(timeout),
+                ..self.inner
+            },
+        }
+    }
+
+    pub fn retry(self, retry: RetryPolicy) -> JsonCryptogramStepBuilder {
+        JsonCryptogramStepBuilder {
+            inner: JsonCryptogramStep {
+                retry: Some(retry),
+                ..self.inner
+            },
+        }
+    }
+
     pub fn finish(self) -> JsonCryptogramStep {
         self.inner
     }
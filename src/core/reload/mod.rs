@@ -0,0 +1,547 @@
+use std::{
+    cmp::Reverse,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::dev::ResourceDef;
+use log::{error, info};
+
+use crate::auth::oauth2::TokenCache;
+use crate::bulkhead::Bulkhead;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::{
+    auth::{AuthConfig, OAuth2Config},
+    cors::CorsConfig,
+    errors::ConfigErrors,
+    load_file, Configuration, EdgeRoute, ListenerConfig, ServiceDefinition, Services, Virtualhosts,
+};
+use crate::upstream::Balancer;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct BoundRoute {
+    pub virtualhost: String,
+    pub hostname: String,
+    pub route: String,
+    pub resource: ResourceDef,
+    pub edge_route: EdgeRoute,
+}
+
+/* How specific a route is: how much of it is literal, and then how few dynamic segments it has.
+ * A route for `/items/new` is tried before one for `/items/{id}`.
+ */
+fn specificity(route: &str) -> (usize, Reverse<usize>) {
+    let mut literal = 0;
+    let mut dynamic = 0;
+    let mut depth = 0;
+    for c in route.chars() {
+        match c {
+            '{' => {
+                if depth == 0 {
+                    dynamic += 1;
+                }
+                depth += 1;
+            }
+            '}' => depth = usize::saturating_sub(depth, 1),
+            _ if depth == 0 => literal += 1,
+            _ => {}
+        }
+    }
+    (literal, Reverse(dynamic))
+}
+
+/* The OAuth2 config of a service that authenticates with one. */
+fn oauth2<'a>(services: &'a Services, name: &str) -> Option<&'a OAuth2Config> {
+    match services.get(name) {
+        Some(ServiceDefinition::Rest {
+            auth: Some(AuthConfig::OAuth2(config)),
+            ..
+        }) => Some(config),
+        _ => None,
+    }
+}
+
+/* Snapshot
+ *
+ * One consistent version of the reloadable parts of the configuration. Requests hold on to the
+ * snapshot they started with, so a reload never changes services or routes mid-evaluation.
+ */
+pub struct Snapshot {
+    pub services: Services,
+    pub virtualhosts: Virtualhosts,
+    pub routes: Vec<BoundRoute>,
+    /* OAuth2 tokens for these services, shared with the next snapshot for services whose
+     * `oauth2` auth doesn't change.
+     */
+    pub tokens: TokenCache,
+    /* Balancers, with the health of each authority. They're shared with the next snapshot for
+     * services whose authorities, `balance` and `health` don't change.
+     */
+    pub balancers: hashbrown::HashMap<String, Arc<Balancer>>,
+    /* Circuit breakers for the services that configure one, shared with the next snapshot if
+     * neither their `circuit-breaker` nor the service's authorities change.
+     */
+    pub breakers: hashbrown::HashMap<String, Arc<CircuitBreaker>>,
+    /* Concurrency limits for the services that configure one. Calls in flight hold on to their
+     * slots across reloads, so each is shared with the next snapshot while its `bulkhead` doesn't
+     * change, rather than handing out a fresh set of slots.
+     */
+    pub bulkheads: hashbrown::HashMap<String, Arc<Bulkhead>>,
+}
+
+impl Snapshot {
+    pub fn new(services: Services, virtualhosts: Virtualhosts) -> Snapshot {
+        Snapshot::build(services, virtualhosts, None)
+    }
+
+    /* A snapshot to replace `previous`, keeping what it knows about services that are still
+     * configured the same way.
+     */
+    pub fn reload(services: Services, virtualhosts: Virtualhosts, previous: &Snapshot) -> Snapshot {
+        Snapshot::build(services, virtualhosts, Some(previous))
+    }
+
+    fn build(
+        services: Services,
+        virtualhosts: Virtualhosts,
+        previous: Option<&Snapshot>,
+    ) -> Snapshot {
+        let mut routes = Vec::new();
+        for (name, vhost) in &virtualhosts {
+            for (route, edge_route) in &vhost.routes {
+                routes.push(BoundRoute {
+                    virtualhost: name.clone(),
+                    hostname: vhost.hostname.clone(),
+                    route: route.clone(),
+                    resource: ResourceDef::new(route.as_str()),
+                    edge_route: edge_route.clone(),
+                });
+            }
+        }
+        // Virtualhosts and routes come out of hash maps in no particular order, but where routes
+        // overlap, the same one must win every time.
+        routes.sort_by_cached_key(|bound| {
+            (
+                Reverse(specificity(&bound.route)),
+                bound.virtualhost.clone(),
+                bound.route.clone(),
+            )
+        });
+        let balancers = services
+            .iter()
+            .map(|(name, service)| {
+                let ServiceDefinition::Rest {
+                    authorities,
+                    balance,
+                    health,
+                    ..
+                } = service;
+                let balancer = previous
+                    .and_then(|previous| previous.balancers.get(name))
+                    .filter(|balancer| {
+                        balancer.configured_as(authorities, balance, health.as_ref())
+                    })
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        Arc::new(Balancer::new(name, authorities, balance, health.as_ref()))
+                    });
+                (name.clone(), balancer)
+            })
+            .collect();
+        let breakers = services
+            .iter()
+            .filter_map(|(name, service)| {
+                let ServiceDefinition::Rest {
+                    authorities,
+                    circuit_breaker,
+                    ..
+                } = service;
+                let config = circuit_breaker.as_ref()?;
+                // Pointing the service somewhere else starts its breaker afresh.
+                let unmoved = |previous: &&Snapshot| {
+                    matches!(
+                        previous.services.get(name),
+                        Some(ServiceDefinition::Rest { authorities: before, .. }) if before == authorities
+                    )
+                };
+                let breaker = previous
+                    .filter(unmoved)
+                    .and_then(|previous| previous.breakers.get(name))
+                    .filter(|breaker| breaker.configured_as(config))
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(CircuitBreaker::new(name, config)));
+                Some((name.clone(), breaker))
+            })
+            .collect();
+        let bulkheads = services
+            .iter()
+            .filter_map(|(name, service)| {
+                let ServiceDefinition::Rest { bulkhead, .. } = service;
+                let config = bulkhead.as_ref()?;
+                let bulkhead = previous
+                    .and_then(|previous| previous.bulkheads.get(name))
+                    .filter(|bulkhead| bulkhead.configured_as(config))
+                    .cloned()
+                    .unwrap_or_else(|| Arc::new(Bulkhead::new(name, config)));
+                Some((name.clone(), bulkhead))
+            })
+            .collect();
+        let tokens = match previous {
+            Some(previous) => previous.tokens.carry_over(|name| {
+                oauth2(&services, name)
+                    .is_some_and(|config| oauth2(&previous.services, name) == Some(config))
+            }),
+            None => TokenCache::default(),
+        };
+        Snapshot {
+            services,
+            virtualhosts,
+            routes,
+            tokens,
+            balancers,
+            breakers,
+            bulkheads,
+        }
+    }
+
+    /* The route for a request arriving on `listener`, among the virtualhosts it serves, the most
+     * specific first.
+     */
+    pub fn route(
+        &self,
+        listener: &ListenerConfig,
+        hostname: &str,
+        path: &str,
+    ) -> Option<&EdgeRoute> {
+        self.bound(listener, hostname, path)
+            .map(|bound| &bound.edge_route)
+    }
+
+    fn bound(&self, listener: &ListenerConfig, hostname: &str, path: &str) -> Option<&BoundRoute> {
+        self.routes
+            .iter()
+            .filter(|bound| listener.serves(&bound.virtualhost))
+            .find(|bound| bound.hostname == hostname && bound.resource.is_match(path))
+    }
+
+    /* The route a loopback service's method is dispatched to, among the virtualhosts it lists,
+     * along with its index in `routes`.
+     */
+    pub fn loopback(&self, virtualhosts: &[String], path: &str) -> Option<(usize, &BoundRoute)> {
+        self.routes.iter().enumerate().find(|(_, bound)| {
+            virtualhosts.contains(&bound.virtualhost) && bound.resource.is_match(path)
+        })
+    }
+
+    /* The `cors` of the virtualhost a request is for. Where several share a hostname, that's the
+     * one whose route the request matches, or if none does, the first by name that sets `cors`.
+     */
+    pub fn cors(
+        &self,
+        listener: &ListenerConfig,
+        hostname: &str,
+        path: &str,
+    ) -> Option<&CorsConfig> {
+        if let Some(bound) = self.bound(listener, hostname, path) {
+            return self.virtualhosts.get(&bound.virtualhost)?.cors.as_ref();
+        }
+        self.virtualhosts
+            .iter()
+            .filter(|(name, vhost)| listener.serves(name) && vhost.hostname == hostname)
+            .filter(|(_, vhost)| vhost.cors.is_some())
+            .min_by_key(|(name, _)| name.as_str())
+            .and_then(|(_, vhost)| vhost.cors.as_ref())
+    }
+}
+
+/* LiveConfig
+ *
+ * Holds the current Snapshot, swapping in a new one when the config file is reloaded. Only
+ * `services` and `virtualhosts` are reloadable; changes to `http` or `events` need a restart.
+ * `sources` are the files (and include directories) the current snapshot was loaded from.
+ * The same `profile` is applied on every reload.
+ */
+pub struct LiveConfig {
+    path: String,
+    profile: Option<String>,
+    current: RwLock<Arc<Snapshot>>,
+    sources: Mutex<Vec<PathBuf>>,
+    reloading: Mutex<()>,
+}
+
+impl LiveConfig {
+    pub fn new(
+        path: &str,
+        profile: Option<String>,
+        services: Services,
+        virtualhosts: Virtualhosts,
+        sources: Vec<PathBuf>,
+    ) -> LiveConfig {
+        LiveConfig {
+            path: path.to_owned(),
+            profile,
+            current: RwLock::new(Arc::new(Snapshot::new(services, virtualhosts))),
+            sources: Mutex::new(sources),
+            reloading: Mutex::new(()),
+        }
+    }
+
+    pub fn current(&self) -> Arc<Snapshot> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /* Load and validate the config file, only replacing the current snapshot on success. The
+     * files to watch are replaced either way.
+     */
+    pub fn reload(&self) -> Result<(), ConfigErrors> {
+        let _guard = self
+            .reloading
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let config = load_file(&self.path, self.profile.as_deref());
+        // Watch whatever this attempt read, even if it failed: the fix may be in a file that
+        // wasn't part of the last good configuration.
+        let sources = match &config {
+            Ok(config) => config.sources.clone(),
+            Err(errors) => errors.sources.clone(),
+        };
+        *self.sources.lock().unwrap_or_else(PoisonError::into_inner) = sources;
+        let Configuration {
+            services,
+            virtualhosts,
+            ..
+        } = config?;
+        let snapshot = Arc::new(Snapshot::reload(services, virtualhosts, &self.current()));
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = snapshot;
+        Ok(())
+    }
+
+    fn reload_and_log(&self, reason: &str) {
+        match self.reload() {
+            Ok(()) => info!("Reloaded {} ({})", self.path, reason),
+            Err(errors) => error!(
+                "Keeping the running configuration, {} failed to load ({}):\n{}",
+                self.path, reason, errors
+            ),
+        }
+    }
+
+    /* Modification times of the main file and everything it pulled in. */
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        let sources = self.sources.lock().unwrap_or_else(PoisonError::into_inner);
+        std::iter::once(Path::new(&self.path))
+            .chain(sources.iter().map(PathBuf::as_path))
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+/* Reload the config on SIGHUP, or whenever one of its files' modification time changes. */
+pub fn watch(live: Arc<LiveConfig>) {
+    let on_signal = live.clone();
+    actix_web::rt::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(err) => {
+                error!("Unable to listen for SIGHUP: {}", err);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            on_signal.reload_and_log("SIGHUP");
+        }
+    });
+
+    actix_web::rt::spawn(async move {
+        let mut last_modified = live.modified();
+        let mut interval = actix_web::rt::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            if live.modified() != last_modified {
+                live.reload_and_log("file changed");
+                last_modified = live.modified();
+            }
+        }
+    });
+}
+
+#[test]
+fn reload_snapshots() {
+    let dir = std::env::temp_dir().join(format!("delegator-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("edge.toml");
+    let config = |routes: &str| {
+        format!(
+            r#"
+[events.user_action]
+queue_url = "noop"
+
+[http]
+host = "0.0.0.0"
+port = 8080
+client = {{ user-agent = "delegator", default-timeout = "30s" }}
+
+[services]
+
+[virtualhosts.shop]
+hostname = "localhost"
+{}
+"#,
+            routes
+        )
+    };
+    let route = |path: &str| {
+        format!(
+            "routes.\"{}\".cryptogram = '{{\"steps\": [{{\"payload\": 1}}]}}'\n",
+            path
+        )
+    };
+    let routes: String = [
+        "/items/{id}",
+        "/items/new",
+        "/{section}/new",
+        "/items/{id}/{tail}*",
+    ]
+    .iter()
+    .map(|path| route(path))
+    .collect();
+    std::fs::write(&path, config(&routes)).unwrap();
+
+    let path = path.to_str().unwrap();
+    let Configuration {
+        services,
+        virtualhosts,
+        sources,
+        ..
+    } = load_file(path, None).unwrap();
+    let live = LiveConfig::new(path, None, services, virtualhosts, sources);
+    let matched = |path: &str| {
+        let snapshot = live.current();
+        let (_, bound) = snapshot.loopback(&[String::from("shop")], path)?;
+        Some(bound.route.clone())
+    };
+
+    // Overlapping routes go to the most specific one.
+    assert_eq!(matched("/items/new").as_deref(), Some("/items/new"));
+    assert_eq!(matched("/items/42").as_deref(), Some("/items/{id}"));
+    assert_eq!(matched("/orders/new").as_deref(), Some("/{section}/new"));
+    assert_eq!(
+        matched("/items/42/a/b").as_deref(),
+        Some("/items/{id}/{tail}*")
+    );
+
+    // An invalid config keeps the running snapshot, a valid one replaces it.
+    let before = live.current();
+    std::fs::write(path, config("routes = 5")).unwrap();
+    assert!(live.reload().is_err());
+    assert!(Arc::ptr_eq(&before, &live.current()));
+
+    std::fs::write(path, config(&route("/orders/{id}"))).unwrap();
+    live.reload().unwrap();
+    assert!(!Arc::ptr_eq(&before, &live.current()));
+    assert_eq!(matched("/items/new"), None);
+    assert_eq!(matched("/orders/7").as_deref(), Some("/orders/{id}"));
+
+    // Files named by a config that failed to load are watched, so fixing them triggers a reload.
+    let orders = dir.join("orders.json");
+    let cryptogram_file = "routes.\"/orders/{id}\".cryptogram-file = \"orders.json\"";
+    std::fs::write(path, config(cryptogram_file)).unwrap();
+    assert!(live.reload().is_err());
+    let before = live.modified();
+    std::fs::write(&orders, r#"{"steps": [{"payload": 2}]}"#).unwrap();
+    assert_ne!(live.modified(), before);
+    live.reload().unwrap();
+    assert!(live.sources.lock().unwrap().contains(&orders));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn carry_state_across_reloads() {
+    let services = |authority: &str| -> Services {
+        toml::from_str(&format!(
+            r#"
+            [pricing]
+            protocol = "rest"
+            scheme = "http"
+            authority = ["{}", "localhost:8082"]
+            health = {{ consecutive-failures = 1 }}
+            circuit-breaker = {{ window = 1, minimum-calls = 1 }}
+            bulkhead = {{ max-concurrent = 1 }}
+            methods.lookup = {{ path = "/lookup/", method = "POST" }}
+            "#,
+            authority
+        ))
+        .unwrap()
+    };
+    let first = Snapshot::new(services("localhost:8081"), Virtualhosts::new());
+    first.balancers["pricing"]
+        .pick(&serde_json::Value::Null)
+        .record(true);
+    first.breakers["pricing"].permit().unwrap().record(true);
+
+    // Services configured the same way keep their state, others start afresh.
+    let second = Snapshot::reload(services("localhost:8081"), Virtualhosts::new(), &first);
+    assert!(Arc::ptr_eq(
+        &first.balancers["pricing"],
+        &second.balancers["pricing"]
+    ));
+    assert_eq!(second.breakers["pricing"].status()["state"], "open");
+    assert!(Arc::ptr_eq(
+        &first.bulkheads["pricing"],
+        &second.bulkheads["pricing"]
+    ));
+    let statuses = second.balancers["pricing"].status();
+    assert!(statuses
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|status| status["state"] == "ejected"));
+    let third = Snapshot::reload(services("localhost:8083"), Virtualhosts::new(), &second);
+    assert!(!Arc::ptr_eq(
+        &second.balancers["pricing"],
+        &third.balancers["pricing"]
+    ));
+    assert!(!Arc::ptr_eq(
+        &second.breakers["pricing"],
+        &third.breakers["pricing"]
+    ));
+}
+
+#[test]
+fn cors_for_shared_hostnames() {
+    let virtualhosts: Virtualhosts = toml::from_str(
+        r#"
+        [shop]
+        hostname = "localhost"
+        cors = ["https://shop.example.com"]
+        routes."/cart".cryptogram = '{"steps": [{"payload": 1}]}'
+
+        [admin]
+        hostname = "localhost"
+        cors = ["https://admin.example.com"]
+        routes."/users".cryptogram = '{"steps": [{"payload": 1}]}'
+        "#,
+    )
+    .unwrap();
+    let snapshot = Snapshot::new(Services::new(), virtualhosts);
+    let listener: ListenerConfig = toml::from_str("").unwrap();
+    let origins =
+        |path: &str| snapshot.cors(&listener, "localhost", path).unwrap().origins[0].clone();
+
+    // The virtualhost the route belongs to, and otherwise the first by name, every time.
+    assert_eq!(origins("/cart"), "https://shop.example.com");
+    assert_eq!(origins("/users"), "https://admin.example.com");
+    assert_eq!(origins("/elsewhere"), "https://admin.example.com");
+}
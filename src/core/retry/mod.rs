@@ -0,0 +1,223 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use actix_web::http::Method;
+use serde_json::Value;
+
+use crate::{
+    config::retry::{Jitter, RetryPolicy, RetryableError},
+    routes::evaluate::EvaluateError,
+    upstream::random,
+};
+
+/* Whether `error` is worth another attempt under `policy`. */
+pub fn retryable(policy: &RetryPolicy, method: &Method, error: &EvaluateError) -> bool {
+    if !method.is_idempotent() && !policy.non_idempotent {
+        return false;
+    }
+    match error {
+        EvaluateError::ClientError(_) => policy.errors.contains(&RetryableError::Connection),
+        EvaluateError::Timeout(_) => policy.errors.contains(&RetryableError::Timeout),
+        EvaluateError::NetworkError(status, _) => policy.statuses.contains(status),
+        _ => false,
+    }
+}
+
+/* The delay before retry number `retry`, counting from 1. */
+pub fn backoff(policy: &RetryPolicy, retry: u32) -> Duration {
+    let exponent = i32::try_from(retry.saturating_sub(1)).unwrap_or(i32::MAX);
+    let delay = (policy.backoff.as_secs_f64() * policy.multiplier.powi(exponent))
+        .min(policy.max_backoff.as_secs_f64());
+    let fraction = random() as f64 / u64::MAX as f64;
+    let delay = match policy.jitter {
+        Jitter::None => delay,
+        Jitter::Full => delay * fraction,
+        Jitter::Equal => delay / 2.0 * (1.0 + fraction),
+    };
+    Duration::try_from_secs_f64(delay).unwrap_or(policy.max_backoff)
+}
+
+/* How much of a delay `jitter` may skip, least first. */
+fn skipped(jitter: Jitter) -> u8 {
+    match jitter {
+        Jitter::None => 0,
+        Jitter::Equal => 1,
+        Jitter::Full => 2,
+    }
+}
+
+/* The policy for a step that sets its own `retry`. Steps can be submitted by whoever calls
+ * `/evaluate`, so they may only be more cautious than the method's policy, or the default one if
+ * the method has none: no more attempts, no shorter backoff or more jitter, no statuses or errors
+ * the method doesn't retry, and no retrying non-idempotent methods unless the method's policy does.
+ */
+pub fn narrowed(step: &RetryPolicy, method: Option<&RetryPolicy>) -> RetryPolicy {
+    let default = RetryPolicy::default();
+    let method = method.unwrap_or(&default);
+    RetryPolicy {
+        max_attempts: step.max_attempts.min(method.max_attempts),
+        backoff: step.backoff.max(method.backoff),
+        max_backoff: step.max_backoff.max(method.max_backoff),
+        multiplier: step.multiplier.max(method.multiplier),
+        jitter: std::cmp::min_by_key(step.jitter, method.jitter, |jitter| skipped(*jitter)),
+        errors: step
+            .errors
+            .iter()
+            .filter(|error| method.errors.contains(error))
+            .copied()
+            .collect(),
+        statuses: step
+            .statuses
+            .iter()
+            .filter(|status| method.statuses.contains(status))
+            .copied()
+            .collect(),
+        non_idempotent: step.non_idempotent && method.non_idempotent,
+    }
+}
+
+/* Call `attempt` until it succeeds, fails in a way the policy doesn't retry, or runs out of
+ * attempts. A `timeout` is a deadline for all of the attempts together: each is passed what's
+ * left of it, and no retry is made that would have to start after it.
+ */
+pub async fn with_retries<F, Fut>(
+    policy: Option<&RetryPolicy>,
+    method: &Method,
+    timeout: Option<Duration>,
+    mut attempt: F,
+) -> Result<Value, EvaluateError>
+where
+    F: FnMut(Option<Duration>) -> Fut,
+    Fut: Future<Output = Result<Value, EvaluateError>>,
+{
+    let Some(policy) = policy else {
+        return attempt(timeout).await;
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let remaining = || deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    let mut attempts = 1;
+    loop {
+        let result = attempt(remaining()).await;
+        let retry = match &result {
+            Err(error) => attempts < policy.max_attempts.get() && retryable(policy, method, error),
+            Ok(_) => false,
+        };
+        if !retry {
+            return result;
+        }
+        let delay = backoff(policy, attempts);
+        if remaining().is_some_and(|remaining| remaining <= delay) {
+            return result;
+        }
+        actix_web::rt::time::sleep(delay).await;
+        attempts += 1;
+    }
+}
+
+#[actix_web::test]
+async fn retry_failed_calls() {
+    use std::cell::Cell;
+
+    use actix_web::http::StatusCode;
+
+    let policy: RetryPolicy = toml::from_str(
+        r#"
+        max-attempts = 3
+        backoff = "1ms"
+        jitter = "none"
+        statuses = [503]
+        "#,
+    )
+    .unwrap();
+    let calls = Cell::new(0);
+    // Fails with `status` until the `succeed_on`th call.
+    let failing = |status: StatusCode, succeed_on: u32| {
+        calls.set(0);
+        let calls = &calls;
+        move |_timeout| async move {
+            calls.set(calls.get() + 1);
+            if calls.get() >= succeed_on {
+                Ok(Value::from(calls.get()))
+            } else {
+                Err(EvaluateError::NetworkError(status, Value::Null))
+            }
+        }
+    };
+
+    let unavailable = StatusCode::SERVICE_UNAVAILABLE;
+    let result = with_retries(Some(&policy), &Method::GET, None, failing(unavailable, 3)).await;
+    assert_eq!(result.unwrap(), 3);
+
+    let result = with_retries(Some(&policy), &Method::GET, None, failing(unavailable, 4)).await;
+    assert!(result.is_err());
+    assert_eq!(calls.get(), 3);
+
+    // Statuses that aren't listed, and methods that aren't idempotent, get one attempt.
+    let not_found = StatusCode::NOT_FOUND;
+    let result = with_retries(Some(&policy), &Method::GET, None, failing(not_found, 2)).await;
+    assert!(result.is_err());
+    assert_eq!(calls.get(), 1);
+    let result = with_retries(Some(&policy), &Method::POST, None, failing(unavailable, 2)).await;
+    assert!(result.is_err());
+    assert_eq!(calls.get(), 1);
+
+    // No retry is made that couldn't start before the deadline.
+    let slow = RetryPolicy {
+        backoff: Duration::from_millis(50),
+        ..policy.clone()
+    };
+    let timeout = Some(Duration::from_millis(20));
+    let result = with_retries(Some(&slow), &Method::GET, timeout, failing(unavailable, 2)).await;
+    assert!(result.is_err());
+    assert_eq!(calls.get(), 1);
+
+    assert_eq!(
+        backoff(&policy, 20),
+        policy.max_backoff,
+        "delays are capped at max-backoff"
+    );
+
+    // A step can't ask for more than the method's policy, or the default one, allows.
+    let greedy = RetryPolicy {
+        max_attempts: std::num::NonZeroU32::new(100).unwrap(),
+        backoff: Duration::ZERO,
+        non_idempotent: true,
+        ..policy.clone()
+    };
+    let capped = narrowed(&greedy, Some(&policy));
+    assert_eq!(capped.max_attempts, policy.max_attempts);
+    assert_eq!(capped.backoff, policy.backoff);
+    assert!(!capped.non_idempotent);
+    let capped = narrowed(&greedy, None);
+    assert_eq!(capped.max_attempts, RetryPolicy::default().max_attempts);
+    assert!(!capped.non_idempotent);
+
+    // Nor retry anything the method doesn't, or with more jitter.
+    let method = RetryPolicy {
+        errors: vec![RetryableError::Timeout],
+        ..policy.clone()
+    };
+    let greedy = RetryPolicy {
+        statuses: vec![
+            StatusCode::INTERNAL_SERVER_ERROR,
+            StatusCode::SERVICE_UNAVAILABLE,
+        ],
+        errors: vec![RetryableError::Connection, RetryableError::Timeout],
+        jitter: Jitter::Full,
+        ..policy.clone()
+    };
+    let capped = narrowed(&greedy, Some(&method));
+    assert_eq!(capped.statuses, [StatusCode::SERVICE_UNAVAILABLE]);
+    assert_eq!(capped.errors, [RetryableError::Timeout]);
+    assert_eq!(capped.jitter, Jitter::None);
+    let capped = narrowed(
+        &RetryPolicy {
+            jitter: Jitter::Equal,
+            ..greedy
+        },
+        None,
+    );
+    assert_eq!(capped.jitter, Jitter::Equal);
+}
@@ -0,0 +1,271 @@
+use std::{future::Future, pin::Pin};
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        Method,
+    },
+    web::Data,
+    Error, HttpResponse,
+};
+
+use super::request_host;
+use crate::{
+    config::{cors::CorsConfig, ListenerConfig},
+    reload::LiveConfig,
+};
+
+type CorsFuture<B> = Pin<Box<dyn Future<Output = Result<ServiceResponse<EitherBody<B>>, Error>>>>;
+
+/* The policy for a request: its virtualhost's `cors`, if set, otherwise the global `http.cors`.
+ * Where virtualhosts share a hostname, it's the one whose route the request is for.
+ */
+fn policy(req: &ServiceRequest) -> CorsConfig {
+    let vhost_policy = match (
+        req.app_data::<Data<LiveConfig>>(),
+        req.app_data::<Data<ListenerConfig>>(),
+        request_host(req.request()),
+    ) {
+        (Some(live), Some(listener), Some(host)) => {
+            live.current().cors(listener, &host, req.path()).cloned()
+        }
+        _ => None,
+    };
+    vhost_policy
+        .or_else(|| {
+            req.app_data::<Data<CorsConfig>>()
+                .map(|cors| cors.get_ref().clone())
+        })
+        .unwrap_or_default()
+}
+
+/* Any origin is allowed with a literal `*`, which browsers won't send credentials with. Validation
+ * rejects `*` with `credentials`, but it's checked here too so a bad policy can't echo back every
+ * origin with credentials allowed.
+ */
+fn allow_origin(headers: &mut HeaderMap, policy: &CorsConfig, origin: &HeaderValue) {
+    if policy.allows_any_origin() {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("*"),
+        );
+        return;
+    }
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    if policy.credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+}
+
+/* Responses differ by origin wherever a policy lists any, even those that turn an origin away or
+ * have no Origin at all, so caches mustn't hand one origin's response to another.
+ */
+fn vary_on_origin(headers: &mut HeaderMap, policy: &CorsConfig) {
+    if !policy.origins.is_empty() {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+fn preflight(req: &ServiceRequest, policy: &CorsConfig, origin: &HeaderValue) -> HttpResponse {
+    let request_method = req
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+        .and_then(|method| method.to_str().ok())
+        .unwrap_or_default();
+    let request_headers = req
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .and_then(|headers| headers.to_str().ok())
+        .unwrap_or_default();
+
+    let allowed = origin
+        .to_str()
+        .is_ok_and(|origin| policy.allows_origin(origin))
+        && policy.allows_method(request_method)
+        && request_headers
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .all(|h| policy.allows_header(h));
+    if !allowed {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let mut res = HttpResponse::Ok().finish();
+    let headers = res.headers_mut();
+    allow_origin(headers, policy, origin);
+    if let Ok(method) = HeaderValue::from_str(request_method) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, method);
+    }
+    if let Ok(value) = HeaderValue::from_str(request_headers) {
+        if !value.is_empty() {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+        }
+    }
+    headers.insert(
+        header::ACCESS_CONTROL_MAX_AGE,
+        HeaderValue::from(policy.max_age.as_secs()),
+    );
+    res
+}
+
+/* CORS middleware, for use with `App::wrap_fn`
+ *
+ * Policies are looked up per-request so that virtualhost overrides follow config reloads.
+ * Preflight requests are answered here; other requests from an allowed origin have the
+ * appropriate headers added to their response, and requests from other origins pass through
+ * untouched, leaving the browser to block them.
+ */
+pub fn handle<S, B>(req: ServiceRequest, srv: &S) -> CorsFuture<B>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let policy = policy(&req);
+    let Some(origin) = req.headers().get(header::ORIGIN).cloned() else {
+        let fut = srv.call(req);
+        return Box::pin(async move {
+            let mut res = fut.await?;
+            vary_on_origin(res.headers_mut(), &policy);
+            Ok(res.map_into_left_body())
+        });
+    };
+
+    if req.method() == Method::OPTIONS
+        && req
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        let mut res = preflight(&req, &policy, &origin);
+        vary_on_origin(res.headers_mut(), &policy);
+        return Box::pin(async move { Ok(req.into_response(res).map_into_right_body()) });
+    }
+
+    let fut = srv.call(req);
+    Box::pin(async move {
+        let mut res = fut.await?;
+        if origin
+            .to_str()
+            .is_ok_and(|origin| policy.allows_origin(origin))
+        {
+            allow_origin(res.headers_mut(), &policy, &origin);
+        }
+        vary_on_origin(res.headers_mut(), &policy);
+        Ok(res.map_into_left_body())
+    })
+}
+
+#[actix_web::test]
+async fn cors_middleware() {
+    use actix_web::{test, web, App};
+
+    let app = |cors: CorsConfig| {
+        test::init_service(
+            App::new()
+                .wrap_fn(handle)
+                .app_data(Data::new(cors))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+    };
+    let header = |res: &ServiceResponse<_>, name| {
+        res.headers()
+            .get(name)
+            .map(|value: &HeaderValue| value.to_str().unwrap().to_owned())
+    };
+
+    // Preflights are answered here, and only for allowed origins, methods and headers.
+    let cors: CorsConfig = serde_json::from_value(serde_json::json!({
+        "origins": ["https://app.example.com"],
+        "methods": ["POST"],
+        "headers": ["content-type"],
+        "credentials": true,
+        "max-age": "60s",
+    }))
+    .unwrap();
+    let service = app(cors).await;
+    let preflight = |origin, method, headers| {
+        test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .insert_header((header::ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, method))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_HEADERS, headers))
+            .to_request()
+    };
+    let res = test::call_service(
+        &service,
+        preflight("https://app.example.com", "POST", "Content-Type"),
+    )
+    .await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN).as_deref(),
+        Some("https://app.example.com")
+    );
+    assert_eq!(
+        header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS).as_deref(),
+        Some("true")
+    );
+    assert_eq!(
+        header(&res, header::ACCESS_CONTROL_ALLOW_METHODS).as_deref(),
+        Some("POST")
+    );
+    assert_eq!(
+        header(&res, header::ACCESS_CONTROL_MAX_AGE).as_deref(),
+        Some("60")
+    );
+    for request in [
+        preflight("https://evil.test", "POST", "content-type"),
+        preflight("https://app.example.com", "DELETE", "content-type"),
+        preflight("https://app.example.com", "POST", "x-other"),
+    ] {
+        let res = test::call_service(&service, request).await;
+        assert_eq!(res.status(), 403);
+        assert_eq!(header(&res, header::VARY).as_deref(), Some("Origin"));
+    }
+
+    // Other requests pass through, with headers added only for allowed origins.
+    let request = |origin| {
+        test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::ORIGIN, origin))
+            .to_request()
+    };
+    let res = test::call_service(&service, request("https://app.example.com")).await;
+    assert_eq!(
+        header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS).as_deref(),
+        Some("true")
+    );
+    assert_eq!(header(&res, header::VARY).as_deref(), Some("Origin"));
+    let res = test::call_service(&service, request("https://evil.test")).await;
+    assert_eq!(res.status(), 200);
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN), None);
+    assert_eq!(header(&res, header::VARY).as_deref(), Some("Origin"));
+    let res = test::call_service(&service, test::TestRequest::post().uri("/").to_request()).await;
+    assert_eq!(header(&res, header::VARY).as_deref(), Some("Origin"));
+
+    // Any origin is answered with `*`, never with credentials, even if a policy asks for them.
+    let cors: CorsConfig = serde_json::from_value(serde_json::json!({
+        "origins": ["*"],
+        "credentials": true,
+    }))
+    .unwrap();
+    let service = app(cors).await;
+    let res = test::call_service(&service, request("https://evil.test")).await;
+    assert_eq!(
+        header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN).as_deref(),
+        Some("*")
+    );
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+    let res = test::call_service(&service, preflight("https://evil.test", "PUT", "x-any")).await;
+    assert_eq!(
+        header(&res, header::ACCESS_CONTROL_ALLOW_ORIGIN).as_deref(),
+        Some("*")
+    );
+    assert_eq!(header(&res, header::ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+}
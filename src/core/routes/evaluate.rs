@@ -3,19 +3,33 @@ use async_trait::async_trait;
 use actix_web::{
     body::BoxBody,
     error::{self, PayloadError},
-    guard,
-    http::{Method, Uri},
+    http::{Method, StatusCode, Uri},
     web::{self, Data, Json},
-    HttpResponse, ResponseError,
+    FromRequest, HttpRequest, HttpResponse, ResponseError,
 };
 use awc::error::{JsonPayloadError, SendRequestError};
 use serde_json::{json, Value};
-use std::{fmt, str::Utf8Error, sync::Arc, time::Duration};
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::Utf8Error,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::sync::Mutex;
 
 use crate::{
+    auth::{authenticate, AuthError},
+    bulkhead::Rejection,
     cache::{hash_value, MemoizationCache},
-    config::{EdgeRoute, HttpClientConfig, ServiceDefinition, Services, Virtualhosts},
+    config::{
+        auth::AuthConfig, request_mapping::MappingError, EdgeRoute, HttpClientConfig,
+        ListenerConfig, ServiceDefinition,
+    },
+    reload::{LiveConfig, Snapshot},
+    retry,
+    routes::request_host,
 };
 
 use json_adapter::language::{make_state, State, StepError, TranslateContext};
@@ -54,14 +68,40 @@ impl std::convert::From<&EvaluateError> for serde_json::Value {
             EvaluateError::InvalidTransition(steps, step) => {
                 json!({"err": "unknown_transition", "steps": steps, "step": step})
             }
-            EvaluateError::NetworkError(context) => context.clone(),
+            EvaluateError::NetworkError(_status, context) => context.clone(),
             EvaluateError::NoStepsSpecified => json!({"err": "no_steps_specified"}),
+            EvaluateError::Timeout(timeout) => {
+                json!({"err": "timeout", "timeout_ms": timeout.as_millis() as u64})
+            }
             EvaluateError::UnknownMethod(service_name, method_name) => {
                 json!({"err": "unknown_method", "service_name": service_name, "method_name": method_name})
             }
             EvaluateError::UnknownService(service_name) => {
                 json!({"err": "unknown_service", "service_name": service_name})
             }
+            EvaluateError::UnknownRoute(service_name, method_name) => {
+                json!({"err": "unknown_route", "service_name": service_name, "method_name": method_name})
+            }
+            EvaluateError::Mapping(MappingError::Missing(name)) => {
+                json!({"err": "missing_parameter", "name": name})
+            }
+            EvaluateError::Mapping(MappingError::NotScalar(name)) => {
+                json!({"err": "invalid_parameter", "name": name})
+            }
+            EvaluateError::Loop(routes) => json!({"err": "loop", "routes": routes}),
+            EvaluateError::Auth(inner) => json!({"err": "auth", "value": inner.to_string()}),
+            EvaluateError::CircuitOpen(service_name) => {
+                json!({"err": "circuit_open", "service_name": service_name})
+            }
+            EvaluateError::ServiceBusy(service_name, Rejection::QueueFull(max_queued)) => {
+                json!({"err": "service_busy", "service_name": service_name, "max_queued": max_queued})
+            }
+            EvaluateError::ServiceBusy(service_name, Rejection::QueueTimeout(timeout)) => {
+                json!({"err": "service_busy", "service_name": service_name, "timeout_ms": timeout.as_millis() as u64})
+            }
+            EvaluateError::UnresolvedSecret(header) => {
+                json!({"err": "unresolved_secret", "header": header})
+            }
             EvaluateError::UriBuilderError(_inner) => json!({"err": "uri_builder_error"}),
             EvaluateError::Utf8Error(_inner) => json!({"err": "utf8_error"}),
         }
@@ -76,14 +116,33 @@ pub enum EvaluateError {
     UnknownStep(usize),
     InvalidStructure(StepError),
     InvalidTransition(Vec<usize>, usize),
-    NetworkError(Value),
+    NetworkError(StatusCode, Value),
     NoStepsSpecified,
+    Mapping(MappingError),
+    Timeout(Duration),
     UnknownMethod(String, String),
     UnknownService(String),
+    UnknownRoute(String, String),
+    Loop(Vec<String>),
+    Auth(AuthError),
+    CircuitOpen(String),
+    ServiceBusy(String, Rejection),
+    UnresolvedSecret(String),
     UriBuilderError(error::HttpError),
     Utf8Error(Utf8Error),
 }
 
+impl EvaluateError {
+    /* Whether the error says something about the health of the authority that was called. */
+    pub fn is_upstream_failure(&self) -> bool {
+        match self {
+            EvaluateError::ClientError(_) | EvaluateError::Timeout(_) => true,
+            EvaluateError::NetworkError(status, _) => status.is_server_error(),
+            _ => false,
+        }
+    }
+}
+
 impl ResponseError for EvaluateError {
     fn error_response(&self) -> HttpResponse<BoxBody> {
         json_error_response(self)
@@ -95,16 +154,17 @@ async fn evaluate(
     cryptogram: Json<JsonCryptogram>,
     client_config: Data<HttpClientConfig>,
     cache_state: Data<Mutex<MemoizationCache>>,
-    services: Data<Services>,
+    live: Data<LiveConfig>,
 ) -> Result<HttpResponse, EvaluateError> {
     let live_client = LiveJsonClient::build(client_config.get_ref());
+    let snapshot = live.current();
 
     let (result, _) = do_evaluate(
         ctx.get_ref(),
         cache_state.into_inner(),
         cryptogram.into_inner(),
         live_client,
-        services.get_ref(),
+        &snapshot,
         make_state(),
     )
     .await?;
@@ -117,9 +177,29 @@ pub trait JsonClient {
         &self,
         method: Method,
         uri: Uri,
-        value: &Value,
+        body: Option<&Value>,
         headers: Vec<(String, String)>,
+        auth: Option<&AuthConfig>,
+        timeout: Option<Duration>,
     ) -> Result<Value, EvaluateError>;
+
+    /* The timeout for calls that don't set one, if the client has one. */
+    fn default_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /* POST a form, eg: to an OAuth2 token endpoint, expecting JSON in return. Clients that
+     * never talk to one needn't implement it.
+     */
+    async fn issue_form(
+        &self,
+        _uri: Uri,
+        _form: &[(&str, &str)],
+        _headers: Vec<(String, String)>,
+        _timeout: Option<Duration>,
+    ) -> Result<Value, EvaluateError> {
+        Err(EvaluateError::Auth(AuthError::Unsupported("posting forms")))
+    }
 }
 
 pub struct LiveJsonClient {
@@ -143,46 +223,103 @@ impl LiveJsonClient {
 
 #[async_trait(?Send)]
 impl JsonClient for LiveJsonClient {
+    fn default_timeout(&self) -> Option<Duration> {
+        Some(self.client_config.default_timeout)
+    }
+
     async fn issue_request(
         &self,
         method: Method,
         uri: Uri,
-        payload: &Value,
+        body: Option<&Value>,
         headers: Vec<(String, String)>,
+        auth: Option<&AuthConfig>,
+        timeout: Option<Duration>,
     ) -> Result<Value, EvaluateError> {
+        let timeout = timeout.unwrap_or(self.client_config.default_timeout);
+        let body = body.map(|body| body.to_string().into_bytes());
+        let mut headers = headers;
+        let uri = match auth {
+            Some(auth) => authenticate(
+                auth,
+                &method,
+                uri,
+                &mut headers,
+                body.as_deref().unwrap_or_default(),
+            )
+            .map_err(EvaluateError::Auth)?,
+            None => uri,
+        };
         let mut req = self
             .client
             .request(method, uri)
-            .insert_header(("User-Agent", self.client_config.user_agent.clone()))
-            .insert_header(("Content-Type", "application/json"));
+            .timeout(timeout)
+            .insert_header(("User-Agent", self.client_config.user_agent.clone()));
+        if body.is_some() {
+            req = req.insert_header(("Content-Type", "application/json"));
+        }
         for pair in headers.iter() {
             req = req.insert_header(pair.clone());
         }
-        let mut result = req
-            .send_json(payload)
-            .await
-            .map_err(EvaluateError::ClientError)?;
-        if !result.status().is_success() {
-            let context = if let Ok(json) = result.json::<Value>().await {
-                json
-            } else {
-                let bytes = result
-                    .body()
-                    .await
-                    .map_err(EvaluateError::InvalidPayloadError)?;
-                let text = std::str::from_utf8(&bytes).map_err(EvaluateError::Utf8Error)?;
-                Value::String(String::from(text))
-            };
+        // The body is sent as the bytes that were signed, not re-serialized.
+        let sent = match body {
+            Some(body) => req.send_body(body).await,
+            None => req.send().await,
+        };
+        json_response(sent, timeout).await
+    }
 
-            return Err(EvaluateError::NetworkError(context));
+    async fn issue_form(
+        &self,
+        uri: Uri,
+        form: &[(&str, &str)],
+        headers: Vec<(String, String)>,
+        timeout: Option<Duration>,
+    ) -> Result<Value, EvaluateError> {
+        let timeout = timeout.unwrap_or(self.client_config.default_timeout);
+        let mut req = self
+            .client
+            .request(Method::POST, uri)
+            .timeout(timeout)
+            .insert_header(("User-Agent", self.client_config.user_agent.clone()));
+        for pair in headers.iter() {
+            req = req.insert_header(pair.clone());
         }
-        result
-            .json::<Value>()
-            .await
-            .map_err(EvaluateError::InvalidJsonError)
+        json_response(req.send_form(&form).await, timeout).await
     }
 }
 
+async fn json_response(
+    sent: Result<awc::ClientResponse, SendRequestError>,
+    timeout: Duration,
+) -> Result<Value, EvaluateError> {
+    let mut result = sent.map_err(|err| match err {
+        SendRequestError::Timeout => EvaluateError::Timeout(timeout),
+        err => EvaluateError::ClientError(err),
+    })?;
+    if !result.status().is_success() {
+        let context = if let Ok(json) = result.json::<Value>().await {
+            json
+        } else {
+            let bytes = result
+                .body()
+                .await
+                .map_err(EvaluateError::InvalidPayloadError)?;
+            let text = std::str::from_utf8(&bytes).map_err(EvaluateError::Utf8Error)?;
+            Value::String(String::from(text))
+        };
+
+        return Err(EvaluateError::NetworkError(result.status(), context));
+    }
+    if result.status() == StatusCode::NO_CONTENT {
+        return Ok(Value::Null);
+    }
+    result
+        .json::<Value>()
+        .await
+        .map_err(EvaluateError::InvalidJsonError)
+}
+
 #[allow(dead_code)]
 struct TestJsonClient;
 
@@ -192,140 +329,331 @@ impl JsonClient for TestJsonClient {
         &self,
         _method: Method,
         _uri: Uri,
-        payload: &Value,
+        body: Option<&Value>,
         _headers: Vec<(String, String)>,
+        _auth: Option<&AuthConfig>,
+        _timeout: Option<Duration>,
     ) -> Result<Value, EvaluateError> {
-        Ok(payload.clone())
+        Ok(body.cloned().unwrap_or_default())
     }
 }
 
 pub async fn do_evaluate<JC: JsonClient>(
     ctx: &TranslateContext,
     memoization_cache: Arc<Mutex<MemoizationCache>>,
-    mut cryptogram: JsonCryptogram,
+    cryptogram: JsonCryptogram,
     json_client: JC,
-    services: &Services,
+    snapshot: &Snapshot,
     translator_state: State,
 ) -> Result<(Value, JsonCryptogram), EvaluateError> {
-    let mut final_result: Option<Value> = None;
-
-    let mut step: usize = 0;
-    while step < cryptogram.steps.len() {
-        let current_step = &cryptogram.steps[step];
-        let service_name = &current_step.service;
-        let method_name = &current_step.method;
-        let payload = &current_step.payload.clone().unwrap_or(Value::Null);
-        let preflight = &current_step.preflight;
-        let postflight = &current_step.postflight;
-        let memoization_prefix = &current_step.memoization_prefix;
-        let headers = &current_step.headers;
-
-        let outgoing_payload = if let Some(pf) = preflight {
-            json_adapter::language::step(ctx, pf, payload, translator_state.clone())
-                .map_err(EvaluateError::InvalidStructure)?
-        } else {
-            payload.clone()
-        };
+    evaluate_steps(
+        ctx,
+        memoization_cache,
+        cryptogram,
+        &json_client,
+        snapshot,
+        translator_state,
+        &[],
+    )
+    .await
+}
 
-        let memo_key = memoization_prefix
-            .clone()
-            .map(|prefix| format!("{}{}", prefix, hash_value(&outgoing_payload)));
+/* Loopback
+ *
+ * A service that lists `virtualhosts` is one of our own: its methods are dispatched straight
+ * into the matching route's cryptogram rather than over the network. `stack` holds the routes
+ * currently being evaluated, as indices into the snapshot's routes; cryptograms can't branch,
+ * so entering one of them again could only ever recurse forever.
+ */
+type Evaluation<'a> =
+    Pin<Box<dyn Future<Output = Result<(Value, JsonCryptogram), EvaluateError>> + 'a>>;
 
-        let maybe_cache = if let Some(key) = memo_key.as_ref() {
-            memoization_cache.lock().await.get(key).cloned()
-        } else {
-            None
-        };
-        let new_payload = if let Some(cached_value) = maybe_cache {
-            cached_value
-        } else if let (Some(service_name), Some(method_name)) = (service_name, method_name) {
-            let service = services
-                .get(service_name)
-                .ok_or_else(|| EvaluateError::UnknownService(service_name.to_owned()))?
-                .to_owned();
-            let new_payload = match service {
-                ServiceDefinition::Rest {
-                    scheme,
-                    authority,
-                    methods,
-                    ..
-                } => {
-                    let method = methods.get(method_name).ok_or_else(|| {
-                        EvaluateError::UnknownMethod(
-                            service_name.to_owned(),
-                            method_name.to_owned(),
-                        )
-                    })?;
-
-                    let uri = Uri::builder()
-                        .scheme(scheme)
-                        .authority(authority)
-                        .path_and_query(method.path.to_owned())
-                        .build()
-                        .map_err(EvaluateError::UriBuilderError)?;
-
-                    let result = json_client
-                        .issue_request(
-                            method.method.clone(),
-                            uri,
-                            &outgoing_payload,
-                            headers.clone().unwrap_or_default(),
-                        )
-                        .await?;
-
-                    if let Some(pf) = postflight {
-                        json_adapter::language::step(ctx, pf, &result, translator_state.clone())
-                            .map_err(EvaluateError::InvalidStructure)?
-                    } else {
-                        result
+fn evaluate_steps<'a, JC: JsonClient>(
+    ctx: &'a TranslateContext,
+    memoization_cache: Arc<Mutex<MemoizationCache>>,
+    mut cryptogram: JsonCryptogram,
+    json_client: &'a JC,
+    snapshot: &'a Snapshot,
+    translator_state: State,
+    stack: &'a [usize],
+) -> Evaluation<'a> {
+    Box::pin(async move {
+        let mut final_result: Option<Value> = None;
+
+        let mut step: usize = 0;
+        while step < cryptogram.steps.len() {
+            let current_step = &cryptogram.steps[step];
+            let service_name = &current_step.service;
+            let method_name = &current_step.method;
+            let payload = &current_step.payload.clone().unwrap_or(Value::Null);
+            let preflight = &current_step.preflight;
+            let postflight = &current_step.postflight;
+            let memoization_prefix = &current_step.memoization_prefix;
+            // Secret references are only resolved in config files, not in submitted cryptograms.
+            let headers = current_step
+                .headers
+                .iter()
+                .flatten()
+                .map(|(name, secret)| match secret.expose() {
+                    Some(value) => Ok((name.to_owned(), value.to_owned())),
+                    None => Err(EvaluateError::UnresolvedSecret(name.to_owned())),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let step_timeout = current_step.timeout;
+            let step_retry = &current_step.retry;
+
+            let outgoing_payload = if let Some(pf) = preflight {
+                json_adapter::language::step(ctx, pf, payload, translator_state.clone())
+                    .map_err(EvaluateError::InvalidStructure)?
+            } else {
+                payload.clone()
+            };
+
+            let memo_key = memoization_prefix
+                .clone()
+                .map(|prefix| format!("{}{}", prefix, hash_value(&outgoing_payload)));
+
+            let maybe_cache = if let Some(key) = memo_key.as_ref() {
+                memoization_cache.lock().await.get(key).cloned()
+            } else {
+                None
+            };
+            let new_payload = if let Some(cached_value) = maybe_cache {
+                cached_value
+            } else if let (Some(service_name), Some(method_name)) = (service_name, method_name) {
+                let service = snapshot
+                    .services
+                    .get(service_name)
+                    .ok_or_else(|| EvaluateError::UnknownService(service_name.to_owned()))?
+                    .to_owned();
+                let new_payload = match service {
+                    ServiceDefinition::Rest {
+                        scheme,
+                        methods,
+                        virtualhosts,
+                        timeout: service_timeout,
+                        auth,
+                        ..
+                    } => {
+                        let method = methods.get(method_name).ok_or_else(|| {
+                            EvaluateError::UnknownMethod(
+                                service_name.to_owned(),
+                                method_name.to_owned(),
+                            )
+                        })?;
+
+                        let request = method
+                            .request(&outgoing_payload)
+                            .map_err(EvaluateError::Mapping)?;
+
+                        let timeout = step_timeout.or(method.timeout).or(service_timeout);
+                        let result = if let Some(virtualhosts) = virtualhosts {
+                            let (route_idx, bound) = snapshot
+                                .loopback(&virtualhosts, request.path_and_query.path())
+                                .ok_or_else(|| {
+                                    EvaluateError::UnknownRoute(
+                                        service_name.to_owned(),
+                                        method_name.to_owned(),
+                                    )
+                                })?;
+                            let mut inner_stack = stack.to_vec();
+                            inner_stack.push(route_idx);
+                            if stack.contains(&route_idx) {
+                                let routes = inner_stack
+                                    .iter()
+                                    .map(|idx| &snapshot.routes[*idx])
+                                    .map(|bound| format!("{}:{}", bound.virtualhost, bound.route))
+                                    .collect();
+                                return Err(EvaluateError::Loop(routes));
+                            }
+                            // The route sees what it would be sent over HTTP: the mapped body.
+                            let route_state = make_state();
+                            let cryptogram = bind_input(
+                                ctx,
+                                bound.edge_route.cryptogram.clone(),
+                                &request.body.unwrap_or_default(),
+                                route_state.clone(),
+                            )?;
+                            let evaluation = evaluate_steps(
+                                ctx,
+                                memoization_cache.clone(),
+                                cryptogram,
+                                json_client,
+                                snapshot,
+                                route_state,
+                                &inner_stack,
+                            );
+                            let (result, _) = match timeout {
+                                Some(timeout) => actix_web::rt::time::timeout(timeout, evaluation)
+                                    .await
+                                    .map_err(|_| EvaluateError::Timeout(timeout))??,
+                                None => evaluation.await?,
+                            };
+                            result
+                        } else {
+                            let balancer =
+                                snapshot.balancers.get(service_name).ok_or_else(|| {
+                                    EvaluateError::UnknownService(service_name.to_owned())
+                                })?;
+                            let breaker = snapshot.breakers.get(service_name);
+                            let bulkhead = snapshot.bulkheads.get(service_name);
+                            let headers = &[request.headers, headers].concat();
+                            let body = request.body.as_ref();
+                            let (scheme, path_and_query) = (&scheme, &request.path_and_query);
+                            let outgoing_payload = &outgoing_payload;
+                            let auth = &auth;
+                            let retry_policy = match step_retry {
+                                Some(step) => Some(retry::narrowed(step, method.retry.as_ref())),
+                                None => method.retry.clone(),
+                            };
+                            // Retries stop at the call's timeout, or the client's if it has none.
+                            let deadline = timeout.or_else(|| json_client.default_timeout());
+                            retry::with_retries(
+                                retry_policy.as_ref(),
+                                &method.method,
+                                deadline,
+                                |timeout| {
+                                    async move {
+                                        // Wait for a slot before asking the breaker, so that a
+                                        // half-open circuit's trials aren't spent queueing.
+                                        // Time spent queueing comes out of the call's own.
+                                        let (_slot, timeout) = match bulkhead {
+                                            Some(bulkhead) => {
+                                                let queued = Instant::now();
+                                                let slot = bulkhead.enter(timeout).await?;
+                                                let timeout = timeout.map(|timeout| {
+                                                    timeout.saturating_sub(queued.elapsed())
+                                                });
+                                                (Some(slot), timeout)
+                                            }
+                                            None => (None, timeout),
+                                        };
+                                        let permit = match breaker {
+                                            Some(breaker) => {
+                                                Some(breaker.permit().ok_or_else(|| {
+                                                    EvaluateError::CircuitOpen(
+                                                        service_name.to_owned(),
+                                                    )
+                                                })?)
+                                            }
+                                            None => None,
+                                        };
+                                        // Each attempt may go to a different authority.
+                                        let upstream = balancer.pick(outgoing_payload);
+                                        let uri = Uri::builder()
+                                            .scheme(scheme.clone())
+                                            .authority(upstream.authority().clone())
+                                            .path_and_query(path_and_query.clone())
+                                            .build()
+                                            .map_err(EvaluateError::UriBuilderError)?;
+                                        let send = |authorization: Option<(String, String)>,
+                                                    timeout: Option<Duration>| {
+                                            json_client.issue_request(
+                                                method.method.clone(),
+                                                uri.clone(),
+                                                body,
+                                                [
+                                                    headers.clone(),
+                                                    authorization.into_iter().collect(),
+                                                ]
+                                                .concat(),
+                                                auth.as_ref(),
+                                                timeout,
+                                            )
+                                        };
+                                        let result = match auth {
+                                            Some(AuthConfig::OAuth2(config)) => {
+                                                snapshot
+                                                    .tokens
+                                                    .authorized(
+                                                        service_name,
+                                                        config,
+                                                        json_client,
+                                                        timeout,
+                                                        |bearer, timeout| {
+                                                            send(Some(bearer), timeout)
+                                                        },
+                                                    )
+                                                    .await
+                                            }
+                                            _ => send(None, timeout).await,
+                                        };
+                                        // Auth errors, eg: from the token endpoint, come before
+                                        // the service is reached, and say nothing about it.
+                                        if !matches!(result, Err(EvaluateError::Auth(_))) {
+                                            let failed = result
+                                                .as_ref()
+                                                .is_err_and(EvaluateError::is_upstream_failure);
+                                            upstream.record(failed);
+                                            if let Some(permit) = permit {
+                                                permit.record(failed);
+                                            }
+                                        }
+                                        result
+                                    }
+                                },
+                            )
+                            .await?
+                        };
+
+                        if let Some(pf) = postflight {
+                            json_adapter::language::step(ctx, pf, &result, translator_state.clone())
+                                .map_err(EvaluateError::InvalidStructure)?
+                        } else {
+                            result
+                        }
                     }
+                };
+                if let Some(key) = memo_key {
+                    memoization_cache.lock().await.insert(
+                        key,
+                        new_payload,
+                        Duration::from_secs(600),
+                    )
+                } else {
+                    new_payload
                 }
+            } else if let Some(pf) = postflight {
+                json_adapter::language::step(ctx, pf, &outgoing_payload, translator_state.clone())
+                    .map_err(EvaluateError::InvalidStructure)?
+            } else {
+                outgoing_payload
             };
-            if let Some(key) = memo_key {
-                memoization_cache
-                    .lock()
-                    .await
-                    .insert(key, new_payload, Duration::from_secs(600))
+
+            let next_idx = step + 1;
+            if next_idx < cryptogram.steps.len() {
+                if cryptogram.steps[next_idx].payload.is_some() {
+                    println!(
+                        "Warning: Discarding payload for step {}: {:?}",
+                        next_idx, cryptogram.steps[next_idx].payload
+                    );
+                }
+                cryptogram.steps[next_idx].payload = Some(new_payload);
             } else {
-                new_payload
+                final_result = Some(new_payload);
             }
-        } else if let Some(pf) = postflight {
-            json_adapter::language::step(ctx, pf, &outgoing_payload, translator_state.clone())
-                .map_err(EvaluateError::InvalidStructure)?
-        } else {
-            outgoing_payload
-        };
 
-        let next_idx = step + 1;
-        if next_idx < cryptogram.steps.len() {
-            if cryptogram.steps[next_idx].payload.is_some() {
-                println!(
-                    "Warning: Discarding payload for step {}: {:?}",
-                    next_idx, cryptogram.steps[next_idx].payload
-                );
-            }
-            cryptogram.steps[next_idx].payload = Some(new_payload);
-        } else {
-            final_result = Some(new_payload);
+            step = next_idx;
         }
 
-        step = next_idx;
-    }
-
-    final_result
-        .map(|v| (v, cryptogram))
-        .ok_or(EvaluateError::NoStepsSpecified)
+        final_result
+            .map(|v| (v, cryptogram))
+            .ok_or(EvaluateError::NoStepsSpecified)
+    })
 }
 
 #[actix_web::test]
 async fn routes_evaluate() {
-    use crate::config::MethodDefinition;
+    use crate::config::path_template::PathTemplate;
+    use crate::config::upstream::Upstream;
+    use crate::config::{MethodDefinition, Services, Virtualhosts};
     use crate::model::cryptogram::JsonCryptogramStep;
-    use actix_web::http::uri::{Authority, PathAndQuery, Scheme};
+    use actix_web::http::uri::{Authority, Scheme};
     use hashbrown::hash_map::DefaultHashBuilder;
     use hashbrown::HashMap;
     use json_adapter::language::Language;
     use serde_json::json;
+    use std::str::FromStr;
 
     let cryptogram = JsonCryptogram {
         steps: vec![
@@ -367,7 +695,8 @@ async fn routes_evaluate() {
         "catalog".to_string(),
         ServiceDefinition::Rest {
             scheme: Scheme::HTTP,
-            authority: Authority::from_static("0:0"),
+            authorities: vec![Upstream::from(Authority::from_static("0:0"))],
+            balance: Default::default(),
             methods: {
                 let mut methods = {
                     let s = DefaultHashBuilder::default();
@@ -377,19 +706,34 @@ async fn routes_evaluate() {
                     "search".to_string(),
                     MethodDefinition {
                         method: Method::POST,
-                        path: PathAndQuery::from_static("/search/"),
+                        path: PathTemplate::from_str("/search/").unwrap(),
+                        timeout: None,
+                        retry: None,
+                        query: Default::default(),
+                        headers: Default::default(),
+                        body: Default::default(),
                     },
                 );
                 methods.insert(
                     "lookup".to_string(),
                     MethodDefinition {
                         method: Method::POST,
-                        path: PathAndQuery::from_static("/product_variants/"),
+                        path: PathTemplate::from_str("/product_variants/").unwrap(),
+                        timeout: None,
+                        retry: None,
+                        query: Default::default(),
+                        headers: Default::default(),
+                        body: Default::default(),
                     },
                 );
                 methods
             },
             virtualhosts: None,
+            timeout: None,
+            auth: None,
+            health: None,
+            circuit_breaker: None,
+            bulkhead: None,
         },
     );
 
@@ -400,7 +744,7 @@ async fn routes_evaluate() {
         memoization_cache,
         cryptogram,
         TestJsonClient,
-        &services,
+        &Snapshot::new(services, Virtualhosts::new()),
         make_state(),
     )
     .await
@@ -415,70 +759,423 @@ async fn routes_evaluate() {
     }
 }
 
+#[actix_web::test]
+async fn routes_evaluate_timeouts() {
+    use crate::config::{Services, Virtualhosts};
+    use crate::model::cryptogram::JsonCryptogramStep;
+
+    struct EchoTimeoutClient;
+
+    #[async_trait(?Send)]
+    impl JsonClient for EchoTimeoutClient {
+        async fn issue_request(
+            &self,
+            _method: Method,
+            _uri: Uri,
+            _body: Option<&Value>,
+            _headers: Vec<(String, String)>,
+            _auth: Option<&AuthConfig>,
+            timeout: Option<Duration>,
+        ) -> Result<Value, EvaluateError> {
+            Ok(json!(timeout.map(|timeout| timeout.as_millis() as u64)))
+        }
+    }
+
+    let services: Services = toml::from_str(
+        r#"
+        [pricing]
+        protocol = "rest"
+        scheme = "http"
+        authority = "localhost:8080"
+        timeout = "300ms"
+        methods.lookup = { path = "/lookup/", method = "POST" }
+        methods.bulk = { path = "/bulk/", method = "POST", timeout = "2s" }
+
+        [catalog]
+        protocol = "rest"
+        scheme = "http"
+        authority = "localhost:8080"
+        methods.explore = { path = "/explore/", method = "POST" }
+        "#,
+    )
+    .unwrap();
+    let snapshot = Snapshot::new(services, Virtualhosts::new());
+
+    let cases = [
+        (JsonCryptogramStep::build("pricing", "lookup"), json!(300)),
+        (JsonCryptogramStep::build("pricing", "bulk"), json!(2000)),
+        (JsonCryptogramStep::build("catalog", "explore"), json!(null)),
+    ];
+    for (step, expected) in cases {
+        let cryptogram = JsonCryptogram {
+            steps: vec![step.payload(json!({})).finish()],
+        };
+        let (value, _) = do_evaluate(
+            &TranslateContext::noop(),
+            Arc::new(MemoizationCache::new()),
+            cryptogram,
+            EchoTimeoutClient,
+            &snapshot,
+            make_state(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(value, expected);
+    }
+
+    let cryptogram = JsonCryptogram {
+        steps: vec![JsonCryptogramStep::build("pricing", "bulk")
+            .payload(json!({}))
+            .timeout(Duration::from_secs(10))
+            .finish()],
+    };
+    let (value, _) = do_evaluate(
+        &TranslateContext::noop(),
+        Arc::new(MemoizationCache::new()),
+        cryptogram,
+        EchoTimeoutClient,
+        &snapshot,
+        make_state(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(value, json!(10000));
+}
+
+#[actix_web::test]
+async fn routes_evaluate_balanced() {
+    use crate::config::{Services, Virtualhosts};
+    use crate::model::cryptogram::JsonCryptogramStep;
+
+    /* Stands in for a server at every authority, answering with its own. */
+    struct EchoAuthorityClient;
+
+    #[async_trait(?Send)]
+    impl JsonClient for EchoAuthorityClient {
+        async fn issue_request(
+            &self,
+            _method: Method,
+            uri: Uri,
+            _body: Option<&Value>,
+            _headers: Vec<(String, String)>,
+            _auth: Option<&AuthConfig>,
+            _timeout: Option<Duration>,
+        ) -> Result<Value, EvaluateError> {
+            Ok(json!(uri.authority().map(|authority| authority.as_str())))
+        }
+    }
+
+    let services: Services = toml::from_str(
+        r#"
+        [catalog]
+        protocol = "rest"
+        scheme = "http"
+        authority = ["localhost:8081", "localhost:8082"]
+        methods.explore = { path = "/explore/", method = "POST" }
+        "#,
+    )
+    .unwrap();
+    let snapshot = Snapshot::new(services, Virtualhosts::new());
+
+    let mut picked = Vec::new();
+    for _ in 0..3 {
+        let cryptogram = JsonCryptogram {
+            steps: vec![JsonCryptogramStep::build("catalog", "explore")
+                .payload(json!({}))
+                .finish()],
+        };
+        let (value, _) = do_evaluate(
+            &TranslateContext::noop(),
+            Arc::new(MemoizationCache::new()),
+            cryptogram,
+            EchoAuthorityClient,
+            &snapshot,
+            make_state(),
+        )
+        .await
+        .unwrap();
+        picked.push(value);
+    }
+    assert_eq!(
+        picked,
+        [
+            json!("localhost:8081"),
+            json!("localhost:8082"),
+            json!("localhost:8081")
+        ]
+    );
+}
+
+#[actix_web::test]
+async fn routes_evaluate_token_failures() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::config::{Services, Virtualhosts};
+    use crate::model::cryptogram::JsonCryptogramStep;
+
+    /* A healthy service behind a token endpoint that's down. */
+    #[derive(Clone, Default)]
+    struct DownTokenServer {
+        requested: Arc<AtomicUsize>,
+    }
+
+    #[async_trait(?Send)]
+    impl JsonClient for DownTokenServer {
+        async fn issue_request(
+            &self,
+            _method: Method,
+            _uri: Uri,
+            _body: Option<&Value>,
+            _headers: Vec<(String, String)>,
+            _auth: Option<&AuthConfig>,
+            _timeout: Option<Duration>,
+        ) -> Result<Value, EvaluateError> {
+            Ok(json!("quoted"))
+        }
+
+        async fn issue_form(
+            &self,
+            _uri: Uri,
+            _form: &[(&str, &str)],
+            _headers: Vec<(String, String)>,
+            _timeout: Option<Duration>,
+        ) -> Result<Value, EvaluateError> {
+            self.requested.fetch_add(1, Ordering::SeqCst);
+            Err(EvaluateError::NetworkError(
+                StatusCode::SERVICE_UNAVAILABLE,
+                Value::Null,
+            ))
+        }
+    }
+
+    let services: Services = toml::from_str(
+        r#"
+        [pricing]
+        protocol = "rest"
+        scheme = "http"
+        authority = ["localhost:8081", "localhost:8082"]
+        health = { consecutive-failures = 1 }
+        circuit-breaker = { window = 1, minimum-calls = 1 }
+        auth = { type = "oauth2", token-url = "http://localhost:8080/token", client-id = "delegator", client-secret = "secret" }
+        methods.quote = { path = "/quote/", method = "POST", retry = { max-attempts = 3, backoff = "1ms", statuses = [503], non-idempotent = true } }
+        "#,
+    )
+    .unwrap();
+    let snapshot = Snapshot::new(services, Virtualhosts::new());
+    let client = DownTokenServer::default();
+
+    for _ in 0..2 {
+        let cryptogram = JsonCryptogram {
+            steps: vec![JsonCryptogramStep::build("pricing", "quote")
+                .payload(json!({}))
+                .finish()],
+        };
+        let result = do_evaluate(
+            &TranslateContext::noop(),
+            Arc::new(MemoizationCache::new()),
+            cryptogram,
+            client.clone(),
+            &snapshot,
+            make_state(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(EvaluateError::Auth(AuthError::TokenRequest(_)))
+        ));
+    }
+
+    // Neither retried nor held against the service.
+    assert_eq!(client.requested.load(Ordering::SeqCst), 2);
+    let authorities = snapshot.balancers["pricing"].status();
+    assert!(authorities
+        .as_array()
+        .unwrap()
+        .iter()
+        .all(|status| status["state"] == "healthy"));
+    assert_eq!(snapshot.breakers["pricing"].status()["state"], "closed");
+}
+
+#[actix_web::test]
+async fn routes_evaluate_loopback() {
+    use crate::config::{Services, Virtualhost, Virtualhosts};
+    use crate::model::cryptogram::JsonCryptogramStep;
+    use json_adapter::language::Language;
+
+    let services: Services = toml::from_str(
+        r#"
+        [catalog]
+        protocol = "rest"
+        scheme = "http"
+        authority = "localhost:8080"
+        virtualhosts = ["internal"]
+        methods.lookup = { path = "/lookup/", method = "POST" }
+        methods.recurse = { path = "/recurse/", method = "POST" }
+        methods.unwrap = { path = "/lookup/", method = "POST", body = { field = "order" } }
+
+        [pricing]
+        protocol = "rest"
+        scheme = "http"
+        authority = "localhost:8081"
+        methods.quote = { path = "/quote/", method = "POST" }
+        "#,
+    )
+    .unwrap();
+    let route = |step: JsonCryptogramStep| EdgeRoute {
+        cryptogram: JsonCryptogram { steps: vec![step] },
+        cryptogram_file: None,
+    };
+    let mut virtualhosts = Virtualhosts::new();
+    virtualhosts.insert(
+        String::from("internal"),
+        Virtualhost {
+            hostname: String::from("localhost"),
+            routes: [
+                (
+                    String::from("/lookup/"),
+                    route(
+                        JsonCryptogramStep::build("pricing", "quote")
+                            .payload(json!(null))
+                            .preflight(Language::at("ids"))
+                            .finish(),
+                    ),
+                ),
+                (
+                    String::from("/recurse/"),
+                    route(
+                        JsonCryptogramStep::build("catalog", "recurse")
+                            .payload(json!(null))
+                            .finish(),
+                    ),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            cors: None,
+            tls: None,
+        },
+    );
+    let snapshot = Snapshot::new(services, virtualhosts);
+    let ctx = TranslateContext::noop();
+    let evaluate = |method: &str, payload: Value| {
+        do_evaluate(
+            &ctx,
+            Arc::new(MemoizationCache::new()),
+            JsonCryptogram {
+                steps: vec![JsonCryptogramStep::build("catalog", method)
+                    .payload(payload)
+                    .finish()],
+            },
+            TestJsonClient,
+            &snapshot,
+            make_state(),
+        )
+    };
+
+    // TestJsonClient echoes the payload, so only the route's preflight can unwrap the ids.
+    let (value, _) = evaluate("lookup", json!({"ids": [1, 2]})).await.unwrap();
+    assert_eq!(value, json!([1, 2]));
+
+    // The route's input is the body the method would send.
+    let (value, _) = evaluate("unwrap", json!({"order": {"ids": [3]}}))
+        .await
+        .unwrap();
+    assert_eq!(value, json!([3]));
+
+    let err = evaluate("recurse", json!(null)).await.unwrap_err();
+    assert_eq!(
+        Value::from(&err),
+        json!({"err": "loop", "routes": ["internal:/recurse/", "internal:/recurse/"]})
+    );
+}
+
+/* A route's input is fed to its first step's preflight, if there is one. */
+fn bind_input(
+    ctx: &TranslateContext,
+    mut cryptogram: JsonCryptogram,
+    input: &Value,
+    translator_state: State,
+) -> Result<JsonCryptogram, EvaluateError> {
+    if let Some(preflight) = cryptogram
+        .steps
+        .first_mut()
+        .and_then(|first| first.preflight.take())
+    {
+        let input = json_adapter::language::step(ctx, &preflight, input, translator_state)
+            .map_err(EvaluateError::InvalidStructure)?;
+        cryptogram.steps[0].payload = Some(input);
+    }
+    Ok(cryptogram)
+}
+
 async fn bound_function(
     ctx: Data<TranslateContext>,
     input: Json<Value>,
     client_config: Data<HttpClientConfig>,
     cache_state: Data<Mutex<MemoizationCache>>,
-    services: Data<Services>,
+    snapshot: &Snapshot,
     edge_route: EdgeRoute,
 ) -> Result<HttpResponse, EvaluateError> {
     let live_client = LiveJsonClient::build(client_config.get_ref());
     let translator_state = make_state();
 
-    let input = input.into_inner();
-    let mut cryptogram = edge_route.cryptogram;
-    if !cryptogram.steps.is_empty() && cryptogram.steps[0].preflight.is_some() {
-        let input = json_adapter::language::step(
-            ctx.get_ref(),
-            &cryptogram.steps[0].preflight.clone().unwrap(),
-            &input,
-            translator_state.clone(),
-        )
-        .map_err(EvaluateError::InvalidStructure)?;
-        cryptogram.steps[0].payload = Some(input);
-        cryptogram.steps[0].preflight = None;
-    };
+    let cryptogram = bind_input(
+        ctx.get_ref(),
+        edge_route.cryptogram,
+        &input.into_inner(),
+        translator_state.clone(),
+    )?;
     let (result, _) = do_evaluate(
         ctx.get_ref(),
         cache_state.into_inner(),
         cryptogram,
         live_client,
-        services.get_ref(),
+        snapshot,
         translator_state,
     )
     .await?;
     Ok(HttpResponse::Ok().json(&result))
 }
 
-pub fn configure(server: &mut web::ServiceConfig, virtualhosts: &Virtualhosts) {
-    let mut server = server;
-
-    for (_name, vhost) in virtualhosts {
-        let host_route = || web::route().guard(guard::Host(vhost.hostname.clone()));
-        for (route, edge_route) in &vhost.routes {
-            let edge_route = edge_route.clone();
-            server = server.route(
-                route,
-                host_route().guard(guard::Post()).to(
-                    move |ctx: Data<TranslateContext>,
-                          input: Json<Value>,
-                          client_config: Data<HttpClientConfig>,
-                          cache_state: Data<Mutex<MemoizationCache>>,
-                          services: Data<Services>| {
-                        bound_function(
-                            ctx,
-                            input,
-                            client_config,
-                            cache_state,
-                            services,
-                            edge_route.clone(),
-                        )
-                    },
-                ),
-            );
+/* Virtualhost routes are looked up per-request in the current snapshot, rather than registered
+ * with actix up front, so that reloading the config can add, change, or remove them.
+ */
+async fn dispatch(
+    req: HttpRequest,
+    payload: web::Payload,
+    ctx: Data<TranslateContext>,
+    client_config: Data<HttpClientConfig>,
+    cache_state: Data<Mutex<MemoizationCache>>,
+    live: Data<LiveConfig>,
+    listener: Data<ListenerConfig>,
+) -> Result<HttpResponse, error::Error> {
+    let snapshot = live.current();
+    let edge_route = match request_host(&req) {
+        Some(host) if req.method() == Method::POST => {
+            snapshot.route(&listener, &host, req.path()).cloned()
         }
-    }
+        _ => None,
+    };
+    let Some(edge_route) = edge_route else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
 
-    server.route("/evaluate", web::post().to(evaluate));
+    let input = Json::<Value>::from_request(&req, &mut payload.into_inner()).await?;
+    let response = bound_function(
+        ctx,
+        input,
+        client_config,
+        cache_state,
+        &snapshot,
+        edge_route,
+    )
+    .await?;
+    Ok(response)
+}
+
+pub fn configure(server: &mut web::ServiceConfig, listener: &ListenerConfig) {
+    if listener.evaluate {
+        server.route("/evaluate", web::post().to(evaluate));
+    }
+    server.default_service(web::to(dispatch));
 }
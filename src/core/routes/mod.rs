@@ -1,10 +1,28 @@
-use actix_web::web;
+use actix_web::{
+    http::{header, Uri},
+    web, HttpRequest,
+};
 
+use crate::config::ListenerConfig;
+
+pub mod cors;
 pub mod errors;
 pub mod evaluate;
+pub mod upstreams;
 
-use crate::config::Virtualhosts;
+/* The Host guard's notion of the request's hostname: the Host header, falling back to the
+ * request target, without the port.
+ */
+pub fn request_host(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host())
+        .and_then(|host| host.parse::<Uri>().ok())
+        .and_then(|uri| uri.host().map(String::from))
+}
 
-pub fn configure(server: &mut web::ServiceConfig, virtualhosts: &Virtualhosts) {
-    server.configure(|server| evaluate::configure(server, virtualhosts));
+pub fn configure(server: &mut web::ServiceConfig, listener: &ListenerConfig) {
+    upstreams::configure(server, listener);
+    evaluate::configure(server, listener);
 }
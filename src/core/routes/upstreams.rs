@@ -0,0 +1,33 @@
+use actix_web::{web, web::Data, HttpResponse};
+use serde_json::{json, Map, Value};
+
+use crate::{config::ListenerConfig, reload::LiveConfig};
+
+/* The state of each service: its authorities' calls in flight, failures, and whether they're in
+ * rotation, and its circuit breaker and bulkhead if it has them.
+ */
+async fn upstreams(live: Data<LiveConfig>) -> HttpResponse {
+    let snapshot = live.current();
+    let services: Map<String, Value> = snapshot
+        .balancers
+        .iter()
+        .map(|(name, balancer)| {
+            let mut status = json!({"authorities": balancer.status()});
+            if let Some(breaker) = snapshot.breakers.get(name) {
+                status["circuit"] = breaker.status();
+            }
+            if let Some(bulkhead) = snapshot.bulkheads.get(name) {
+                status["bulkhead"] = bulkhead.status();
+            }
+            (name.clone(), status)
+        })
+        .collect();
+    HttpResponse::Ok().json(services)
+}
+
+/* Served alongside `/evaluate`, on the listeners meant for internal callers. */
+pub fn configure(server: &mut web::ServiceConfig, listener: &ListenerConfig) {
+    if listener.evaluate {
+        server.route("/upstreams", web::get().to(upstreams));
+    }
+}
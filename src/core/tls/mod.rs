@@ -0,0 +1,322 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use hashbrown::HashMap;
+use log::{error, info};
+use openssl::{
+    error::ErrorStack,
+    ssl::{
+        select_next_proto, AlpnError, NameType, SniError, SslAcceptor, SslAcceptorBuilder,
+        SslContext, SslFiletype, SslMethod,
+    },
+};
+
+use crate::{
+    config::{ListenerConfig, TlsConfig},
+    reload::LiveConfig,
+};
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug)]
+pub struct TlsError {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+    pub inner: ErrorStack,
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unable to load certificate {} with key {}: {}",
+            self.certificate.display(),
+            self.key.display(),
+            self.inner
+        )
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+fn acceptor_builder(tls: &TlsConfig) -> Result<SslAcceptorBuilder, TlsError> {
+    let build = || {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        builder.set_certificate_chain_file(&tls.certificate)?;
+        builder.set_private_key_file(&tls.key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+        // Contexts chosen by SNI replace the listener's own, ALPN callback included.
+        builder.set_alpn_select_callback(|_, client| {
+            select_next_proto(b"\x02h2\x08http/1.1", client).ok_or(AlpnError::NOACK)
+        });
+        Ok(builder)
+    };
+    build().map_err(|inner| TlsError {
+        certificate: tls.certificate.clone(),
+        key: tls.key.clone(),
+        inner,
+    })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/* Everything that goes into building the contexts, so we can tell when they need rebuilding:
+ * the certificates each hostname maps to, and when those files were last modified.
+ */
+type Fingerprint = Vec<(
+    Option<String>,
+    TlsConfig,
+    Option<SystemTime>,
+    Option<SystemTime>,
+)>;
+
+struct Contexts {
+    default: SslContext,
+    by_hostname: HashMap<String, SslContext>,
+}
+
+/* CertStore
+ *
+ * The certificates served by a TLS listener: its own certificate by default, or the `tls`
+ * certificate of the virtualhost whose hostname the client asked for via SNI. Certificates
+ * are re-read when their files change, or when a config reload changes the mapping.
+ */
+pub struct CertStore {
+    default: TlsConfig,
+    listener: ListenerConfig,
+    live: Arc<LiveConfig>,
+    fingerprint: Mutex<Fingerprint>,
+    contexts: RwLock<Contexts>,
+}
+
+impl CertStore {
+    fn fingerprint(
+        default: &TlsConfig,
+        listener: &ListenerConfig,
+        live: &LiveConfig,
+    ) -> Fingerprint {
+        let snapshot = live.current();
+        let mut entries: Vec<(Option<String>, TlsConfig)> = vec![(None, default.clone())];
+        for (name, vhost) in &snapshot.virtualhosts {
+            if let (true, Some(tls)) = (listener.serves(name), &vhost.tls) {
+                entries.push((Some(vhost.hostname.clone()), tls.clone()));
+            }
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+            .into_iter()
+            .map(|(hostname, tls)| {
+                let certificate = modified(&tls.certificate);
+                let key = modified(&tls.key);
+                (hostname, tls, certificate, key)
+            })
+            .collect()
+    }
+
+    fn build(fingerprint: &Fingerprint) -> Result<Contexts, TlsError> {
+        let mut default = None;
+        let mut by_hostname = HashMap::new();
+        for (hostname, tls, _, _) in fingerprint {
+            let context = acceptor_builder(tls)?.build().into_context();
+            match hostname {
+                Some(hostname) => {
+                    by_hostname.insert(hostname.clone(), context);
+                }
+                None => default = Some(context),
+            }
+        }
+        let default = default.expect("The default certificate is always part of the fingerprint");
+        Ok(Contexts {
+            default,
+            by_hostname,
+        })
+    }
+
+    pub fn load(
+        default: &TlsConfig,
+        listener: ListenerConfig,
+        live: Arc<LiveConfig>,
+    ) -> Result<CertStore, TlsError> {
+        let fingerprint = CertStore::fingerprint(default, &listener, &live);
+        let contexts = CertStore::build(&fingerprint)?;
+        Ok(CertStore {
+            default: default.clone(),
+            listener,
+            live,
+            fingerprint: Mutex::new(fingerprint),
+            contexts: RwLock::new(contexts),
+        })
+    }
+
+    fn context(&self, hostname: Option<&str>) -> SslContext {
+        let contexts = self.contexts.read().unwrap_or_else(PoisonError::into_inner);
+        hostname
+            .and_then(|hostname| contexts.by_hostname.get(hostname))
+            .unwrap_or(&contexts.default)
+            .clone()
+    }
+
+    /* Rebuild the contexts if anything changed, keeping the current ones if that fails. A
+     * failed rebuild isn't retried until the files or the config change again.
+     */
+    fn refresh(&self) {
+        let fingerprint = CertStore::fingerprint(&self.default, &self.listener, &self.live);
+        let mut last = self
+            .fingerprint
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if *last == fingerprint {
+            return;
+        }
+        let result = CertStore::build(&fingerprint);
+        *last = fingerprint;
+        match result {
+            Ok(contexts) => {
+                *self
+                    .contexts
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = contexts;
+                info!("Reloaded TLS certificates");
+            }
+            Err(err) => error!("Keeping the current TLS certificates: {}", err),
+        }
+    }
+
+    /* An acceptor for `HttpServer::bind_openssl`, which picks a certificate per connection. */
+    pub fn acceptor(store: Arc<CertStore>) -> Result<SslAcceptorBuilder, TlsError> {
+        let mut builder = acceptor_builder(&store.default)?;
+        builder.set_servername_callback(move |ssl, _alert| {
+            let context = store.context(ssl.servername(NameType::HOST_NAME));
+            ssl.set_ssl_context(&context)
+                .map_err(|_err| SniError::ALERT_FATAL)
+        });
+        Ok(builder)
+    }
+}
+
+pub fn watch(store: Arc<CertStore>) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            store.refresh();
+        }
+    });
+}
+
+#[test]
+fn select_and_refresh_certificates() {
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::PKey,
+        x509::{X509Builder, X509NameBuilder},
+    };
+
+    let dir = std::env::temp_dir().join(format!("delegator-tls-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("certs")).unwrap();
+    // A self-signed certificate for `name`, written to certs/<file>.pem and certs/<file>.key.
+    let issue = |file: &str, name: &str| {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        builder.set_issuer_name(&subject).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certs = dir.join("certs");
+        let pem = certs.join(format!("{}.pem", file));
+        std::fs::write(&pem, builder.build().to_pem().unwrap()).unwrap();
+        std::fs::write(
+            certs.join(format!("{}.key", file)),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        // Make sure the change is visible even if it lands within the same mtime tick.
+        let later = SystemTime::now() + Duration::from_secs(1);
+        let file = std::fs::File::options().write(true).open(&pem).unwrap();
+        file.set_modified(later).unwrap();
+    };
+    let served = |store: &CertStore, hostname: Option<&str>| {
+        let context = store.context(hostname);
+        let certificate = context.certificate().unwrap();
+        let name = certificate.subject_name();
+        let common_name = name.entries_by_nid(Nid::COMMONNAME).next().unwrap();
+        common_name.data().to_string().unwrap()
+    };
+    issue("default", "default");
+    issue("shop", "shop.example.com");
+
+    // Paths are relative to the config file.
+    let path = dir.join("edge.toml");
+    std::fs::write(
+        &path,
+        r#"
+        [events.user_action]
+        queue_url = "noop"
+
+        [http]
+        host = "127.0.0.1"
+        port = 8443
+        tls = { certificate = "certs/default.pem", key = "certs/default.key" }
+        client = { user-agent = "delegator/0.1.0", default-timeout = "30s" }
+
+        [services]
+
+        [virtualhosts.shop]
+        hostname = "shop.example.com"
+        routes = {}
+        tls = { certificate = "certs/shop.pem", key = "certs/shop.key" }
+        "#,
+    )
+    .unwrap();
+    let path = path.to_str().unwrap();
+    let config = crate::config::load_file(path, None).unwrap();
+    let live = Arc::new(LiveConfig::new(
+        path,
+        None,
+        config.services,
+        config.virtualhosts,
+        config.sources,
+    ));
+    let listener = config.http.listeners().remove(0);
+    let store = CertStore::load(config.http.tls.as_ref().unwrap(), listener, live).unwrap();
+
+    // Clients asking for a virtualhost's hostname get its certificate, everyone else the default.
+    assert_eq!(served(&store, Some("shop.example.com")), "shop.example.com");
+    assert_eq!(served(&store, Some("other.example.com")), "default");
+    assert_eq!(served(&store, None), "default");
+
+    // Renewed certificates are picked up, and broken ones leave the current ones in place.
+    issue("shop", "renewed.example.com");
+    store.refresh();
+    assert_eq!(
+        served(&store, Some("shop.example.com")),
+        "renewed.example.com"
+    );
+    std::fs::write(dir.join("certs/default.pem"), "not a certificate").unwrap();
+    store.refresh();
+    assert_eq!(served(&store, None), "default");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
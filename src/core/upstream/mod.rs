@@ -0,0 +1,617 @@
+use std::{
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::http::{uri::Authority, Uri};
+use log::{info, warn};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{
+        health::HealthConfig,
+        upstream::{Balance, Upstream},
+        HttpClientConfig, ServiceDefinition,
+    },
+    reload::{LiveConfig, Snapshot},
+};
+
+/* How often the prober checks whether any service is due for its health checks. */
+const PROBE_TICK: Duration = Duration::from_secs(1);
+
+/* Points on the hash ring per unit of weight. */
+const RING_POINTS: u32 = 64;
+
+fn ring_hash(data: &[u8]) -> u64 {
+    let digest = Sha256::digest(data);
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
+
+/* std's RandomState is seeded randomly, and differently for every instance. */
+pub(crate) fn random() -> u64 {
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/* Health of one authority, from probes and from the calls made to it. */
+struct EndpointHealth {
+    probe_healthy: bool,
+    probe_streak: u32,
+    consecutive_failures: u32,
+    ejections: u32,
+    ejected_until: Option<Instant>,
+    recovering_since: Option<Instant>,
+}
+
+impl Default for EndpointHealth {
+    fn default() -> Self {
+        EndpointHealth {
+            probe_healthy: true,
+            probe_streak: 0,
+            consecutive_failures: 0,
+            ejections: 0,
+            ejected_until: None,
+            recovering_since: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Availability {
+    Available,
+    /* Back in rotation for part of the slow start, taking this share of its calls. */
+    Recovering(f64),
+    Ejected(Duration),
+    Unhealthy,
+}
+
+/* Balancer
+ *
+ * Picks one of a service's authorities for each call, and counts the calls in flight to each.
+ * With a `health` config, authorities that are failing are left out until they recover.
+ */
+pub struct Balancer {
+    service: String,
+    balance: Balance,
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    in_flight: Vec<AtomicUsize>,
+    current_weights: Mutex<Vec<i64>>,
+    ring: Vec<(u64, usize)>,
+    health_config: Option<HealthConfig>,
+    health: Vec<Mutex<EndpointHealth>>,
+    next_probe: Mutex<Option<Instant>>,
+}
+
+/* Pick
+ *
+ * The authority chosen for a call, counted as in flight until dropped.
+ */
+pub struct Pick<'a> {
+    balancer: &'a Balancer,
+    index: usize,
+}
+
+impl Pick<'_> {
+    pub fn authority(&self) -> &Authority {
+        &self.balancer.upstreams[self.index].authority
+    }
+
+    /* Count the outcome of the call towards passive health checking. */
+    pub fn record(&self, failed: bool) {
+        self.balancer.record(self.index, failed);
+    }
+}
+
+impl Drop for Pick<'_> {
+    fn drop(&mut self) {
+        self.balancer.in_flight[self.index].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Balancer {
+    pub fn new(
+        service: &str,
+        upstreams: &[Upstream],
+        balance: &Balance,
+        health: Option<&HealthConfig>,
+    ) -> Balancer {
+        let mut ring = Vec::new();
+        if let Balance::ConsistentHash(_) = balance {
+            for (index, upstream) in upstreams.iter().enumerate() {
+                // Validation keeps weights far below overflowing, but a bad one mustn't panic.
+                for point in 0..upstream.weight.get().saturating_mul(RING_POINTS) {
+                    let key = format!("{}#{}", upstream.authority, point);
+                    ring.push((ring_hash(key.as_bytes()), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Balancer {
+            service: service.to_owned(),
+            balance: balance.clone(),
+            upstreams: upstreams.to_vec(),
+            next: AtomicUsize::new(0),
+            in_flight: upstreams.iter().map(|_| AtomicUsize::new(0)).collect(),
+            current_weights: Mutex::new(vec![0; upstreams.len()]),
+            ring,
+            health_config: health.cloned(),
+            health: upstreams.iter().map(|_| Mutex::default()).collect(),
+            next_probe: Mutex::new(None),
+        }
+    }
+
+    /* Whether this balancer was built from these settings, so its state can outlive a reload. */
+    pub fn configured_as(
+        &self,
+        upstreams: &[Upstream],
+        balance: &Balance,
+        health: Option<&HealthConfig>,
+    ) -> bool {
+        self.upstreams == upstreams
+            && self.balance == *balance
+            && self.health_config.as_ref() == health
+    }
+
+    pub fn pick(&self, payload: &Value) -> Pick<'_> {
+        let eligible = self.eligible();
+        let index = match &self.balance {
+            Balance::RoundRobin => self.round_robin(&eligible),
+            Balance::Random => {
+                let candidates: Vec<usize> = (0..self.upstreams.len())
+                    .filter(|index| eligible[*index])
+                    .collect();
+                candidates[random() as usize % candidates.len()]
+            }
+            Balance::Weighted => self.weighted(&eligible),
+            Balance::LeastInFlight => self.least_in_flight(&eligible),
+            // Calls without a value for the field have no affinity.
+            Balance::ConsistentHash(field) => match field.get(payload) {
+                Some(Value::String(key)) => self.hashed(key, &eligible),
+                Some(key) => self.hashed(&key.to_string(), &eligible),
+                None => self.round_robin(&eligible),
+            },
+        };
+        self.in_flight[index].fetch_add(1, Ordering::SeqCst);
+        Pick {
+            balancer: self,
+            index,
+        }
+    }
+
+    /* Which authorities may take this call. Recovering ones take a growing share, and if none
+     * are healthy, all of them are tried rather than failing every call.
+     */
+    fn eligible(&self) -> Vec<bool> {
+        let Some(config) = &self.health_config else {
+            return vec![true; self.upstreams.len()];
+        };
+        let availability: Vec<Availability> = (0..self.upstreams.len())
+            .map(|index| self.availability(index, config))
+            .collect();
+        let eligible: Vec<bool> = availability
+            .iter()
+            .map(|availability| match availability {
+                Availability::Available => true,
+                Availability::Recovering(share) => (random() as f64 / u64::MAX as f64) < *share,
+                Availability::Ejected(_) | Availability::Unhealthy => false,
+            })
+            .collect();
+        if eligible.contains(&true) {
+            return eligible;
+        }
+        let in_rotation: Vec<bool> = availability
+            .iter()
+            .map(|availability| matches!(availability, Availability::Recovering(_)))
+            .collect();
+        if in_rotation.contains(&true) {
+            return in_rotation;
+        }
+        vec![true; self.upstreams.len()]
+    }
+
+    fn health(&self, index: usize) -> MutexGuard<'_, EndpointHealth> {
+        self.health[index]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn availability(&self, index: usize, config: &HealthConfig) -> Availability {
+        let now = Instant::now();
+        let mut health = self.health(index);
+        if let Some(until) = health.ejected_until {
+            if now < until {
+                return Availability::Ejected(until - now);
+            }
+            health.ejected_until = None;
+            health.recovering_since = Some(until);
+            info!(
+                "Readmitting {} to {} after ejection",
+                self.upstreams[index].authority, self.service
+            );
+        }
+        if !health.probe_healthy {
+            return Availability::Unhealthy;
+        }
+        if let Some(since) = health.recovering_since {
+            let elapsed = now.saturating_duration_since(since);
+            if elapsed < config.slow_start {
+                let share = elapsed.as_secs_f64() / config.slow_start.as_secs_f64();
+                return Availability::Recovering(share.max(0.1));
+            }
+            health.recovering_since = None;
+        }
+        Availability::Available
+    }
+
+    /* Passive health checking: eject an authority after too many failed calls in a row, for
+     * longer each time it happens.
+     */
+    fn record(&self, index: usize, failed: bool) {
+        let Some(config) = &self.health_config else {
+            return;
+        };
+        let mut health = self.health(index);
+        if !failed {
+            health.consecutive_failures = 0;
+            if health.recovering_since.is_none() && health.ejected_until.is_none() {
+                health.ejections = 0;
+            }
+            return;
+        }
+        health.consecutive_failures += 1;
+        if health.ejected_until.is_some()
+            || health.consecutive_failures < config.consecutive_failures.get()
+        {
+            return;
+        }
+        health.ejections += 1;
+        let ejection = config
+            .ejection_time
+            .saturating_mul(health.ejections)
+            .min(config.max_ejection_time);
+        health.ejected_until = Some(Instant::now() + ejection);
+        health.consecutive_failures = 0;
+        warn!(
+            "Ejecting {} from {} for {:?}, after {} failed calls in a row",
+            self.upstreams[index].authority, self.service, ejection, config.consecutive_failures
+        );
+    }
+
+    /* Active health checking: count a probe's outcome, taking the authority out of rotation or
+     * bringing it back once enough probes in a row agree.
+     */
+    pub fn probed(&self, index: usize, healthy: bool) {
+        let Some(probe) = self
+            .health_config
+            .as_ref()
+            .and_then(|config| config.probe.as_ref())
+        else {
+            return;
+        };
+        let mut health = self.health(index);
+        if healthy == health.probe_healthy {
+            health.probe_streak = 0;
+            return;
+        }
+        health.probe_streak += 1;
+        let threshold = if healthy {
+            probe.healthy_threshold
+        } else {
+            probe.unhealthy_threshold
+        };
+        if health.probe_streak < threshold.get() {
+            return;
+        }
+        health.probe_healthy = healthy;
+        health.probe_streak = 0;
+        let authority = &self.upstreams[index].authority;
+        if healthy {
+            health.recovering_since = Some(Instant::now());
+            info!(
+                "{} in {} passed {} health checks, returning to rotation",
+                authority, self.service, threshold
+            );
+        } else {
+            warn!(
+                "{} in {} failed {} health checks, leaving rotation",
+                authority, self.service, threshold
+            );
+        }
+    }
+
+    /* Whether the service's authorities are due to be probed, scheduling the next round if so. */
+    fn probe_due(&self) -> bool {
+        let Some(probe) = self
+            .health_config
+            .as_ref()
+            .and_then(|config| config.probe.as_ref())
+        else {
+            return false;
+        };
+        let now = Instant::now();
+        let mut next_probe = self
+            .next_probe
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if next_probe.is_some_and(|next_probe| now < next_probe) {
+            return false;
+        }
+        *next_probe = Some(now + probe.interval);
+        true
+    }
+
+    fn round_robin(&self, eligible: &[bool]) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.upstreams.len())
+            .map(|offset| (start + offset) % self.upstreams.len())
+            .find(|index| eligible[*index])
+            .unwrap_or(start % self.upstreams.len())
+    }
+
+    /* Smooth weighted round-robin, as in nginx: heavier authorities are picked more often, but
+     * their turns are spread out rather than taken all in a row.
+     */
+    fn weighted(&self, eligible: &[bool]) -> usize {
+        let mut current = self
+            .current_weights
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut total = 0;
+        let mut best = None;
+        for (index, upstream) in self.upstreams.iter().enumerate() {
+            if !eligible[index] {
+                continue;
+            }
+            let weight = i64::from(upstream.weight.get());
+            current[index] += weight;
+            total += weight;
+            match best {
+                Some(best) if current[index] <= current[best] => {}
+                _ => best = Some(index),
+            }
+        }
+        let best = best.unwrap_or_default();
+        current[best] -= total;
+        best
+    }
+
+    /* The authority with the fewest calls in flight, starting the search from a different one each
+     * time so that ties are spread out.
+     */
+    fn least_in_flight(&self, eligible: &[bool]) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.upstreams.len())
+            .map(|offset| (start + offset) % self.upstreams.len())
+            .filter(|index| eligible[*index])
+            .min_by_key(|index| self.in_flight[*index].load(Ordering::SeqCst))
+            .unwrap_or(start % self.upstreams.len())
+    }
+
+    /* The first eligible authority clockwise from the key's point on the ring. */
+    fn hashed(&self, key: &str, eligible: &[bool]) -> usize {
+        let hash = ring_hash(key.as_bytes());
+        let point = self.ring.partition_point(|(point, _)| *point < hash);
+        self.ring[point..]
+            .iter()
+            .chain(&self.ring[..point])
+            .map(|(_, index)| *index)
+            .find(|index| eligible[*index])
+            .unwrap_or_default()
+    }
+
+    /* Each authority's state, for the introspection endpoint. */
+    pub fn status(&self) -> Value {
+        let statuses: Vec<Value> = self
+            .upstreams
+            .iter()
+            .enumerate()
+            .map(|(index, upstream)| {
+                let availability = match &self.health_config {
+                    Some(config) => self.availability(index, config),
+                    None => Availability::Available,
+                };
+                let health = self.health(index);
+                let mut status = json!({
+                    "authority": upstream.authority.as_str(),
+                    "weight": upstream.weight.get(),
+                    "in_flight": self.in_flight[index].load(Ordering::SeqCst),
+                    "consecutive_failures": health.consecutive_failures,
+                    "ejections": health.ejections,
+                });
+                let state = match availability {
+                    Availability::Available => json!("healthy"),
+                    Availability::Recovering(share) => {
+                        status["share"] = json!(share);
+                        json!("recovering")
+                    }
+                    Availability::Ejected(remaining) => {
+                        status["ejected_ms"] = json!(remaining.as_millis() as u64);
+                        json!("ejected")
+                    }
+                    Availability::Unhealthy => json!("unhealthy"),
+                };
+                status["state"] = state;
+                status
+            })
+            .collect();
+        Value::from(statuses)
+    }
+}
+
+async fn probe(
+    client: awc::Client,
+    user_agent: String,
+    snapshot: Arc<Snapshot>,
+    service: String,
+    index: usize,
+    uri: Uri,
+) {
+    let Some(balancer) = snapshot.balancers.get(&service) else {
+        return;
+    };
+    let Some(probe) = balancer
+        .health_config
+        .as_ref()
+        .and_then(|config| config.probe.as_ref())
+    else {
+        return;
+    };
+    let response = client
+        .request(probe.method.clone(), uri)
+        .timeout(probe.timeout)
+        .insert_header(("User-Agent", user_agent))
+        .send()
+        .await;
+    let healthy = matches!(response, Ok(response) if response.status().is_success());
+    balancer.probed(index, healthy);
+}
+
+/* Probe the authorities of services with a `health.probe`, in the current snapshot. */
+pub fn watch(live: Arc<LiveConfig>, client_config: HttpClientConfig) {
+    actix_web::rt::spawn(async move {
+        let client = awc::ClientBuilder::new().finish();
+        let mut interval = actix_web::rt::time::interval(PROBE_TICK);
+        loop {
+            interval.tick().await;
+            let snapshot = live.current();
+            for (name, balancer) in &snapshot.balancers {
+                if !balancer.probe_due() {
+                    continue;
+                }
+                let (Some(ServiceDefinition::Rest { scheme, .. }), Some(probe)) = (
+                    snapshot.services.get(name),
+                    balancer
+                        .health_config
+                        .as_ref()
+                        .and_then(|config| config.probe.as_ref()),
+                ) else {
+                    continue;
+                };
+                for (index, upstream) in balancer.upstreams.iter().enumerate() {
+                    let uri = Uri::builder()
+                        .scheme(scheme.clone())
+                        .authority(upstream.authority.clone())
+                        .path_and_query(probe.path.clone())
+                        .build();
+                    let Ok(uri) = uri else {
+                        continue;
+                    };
+                    actix_web::rt::spawn(self::probe(
+                        client.clone(),
+                        client_config.user_agent.clone(),
+                        snapshot.clone(),
+                        name.clone(),
+                        index,
+                        uri,
+                    ));
+                }
+            }
+        }
+    });
+}
+
+#[test]
+fn balance_upstreams() {
+    #[derive(serde::Deserialize)]
+    struct Service {
+        #[serde(deserialize_with = "crate::config::upstream::deserialize")]
+        authority: Vec<Upstream>,
+    }
+    let Service {
+        authority: upstreams,
+    } = toml::from_str(r#"authority = ["a:8080", { authority = "b:8080", weight = 2 }, "c:8080"]"#)
+        .unwrap();
+    let picks = |balancer: &Balancer, payloads: &[Value]| -> Vec<String> {
+        payloads
+            .iter()
+            .map(|payload| balancer.pick(payload).authority().host().to_owned())
+            .collect()
+    };
+    let empty = vec![json!({}); 4];
+
+    let round_robin = Balancer::new("catalog", &upstreams, &Balance::RoundRobin, None);
+    assert_eq!(picks(&round_robin, &empty), ["a", "b", "c", "a"]);
+
+    let weighted = Balancer::new("catalog", &upstreams, &Balance::Weighted, None);
+    assert_eq!(picks(&weighted, &empty), ["b", "a", "c", "b"]);
+
+    let least_in_flight = Balancer::new("catalog", &upstreams, &Balance::LeastInFlight, None);
+    let held = least_in_flight.pick(&json!({}));
+    assert_eq!(held.authority().host(), "a");
+    assert_eq!(picks(&least_in_flight, &empty[..2]), ["b", "c"]);
+    assert_eq!(least_in_flight.status()[0]["in_flight"], 1);
+    drop(held);
+
+    let field = crate::config::request_mapping::FieldPath::parse("user.id").unwrap();
+    let hashed = Balancer::new("catalog", &upstreams, &Balance::ConsistentHash(field), None);
+    let users: Vec<Value> = (0..32).map(|id| json!({"user": {"id": id}})).collect();
+    let first = picks(&hashed, &users);
+    assert_eq!(picks(&hashed, &users), first);
+    assert!(["a", "b", "c"]
+        .iter()
+        .all(|host| first.iter().any(|picked| picked == host)));
+
+    // Removing an authority only moves the keys that were on it.
+    let without_c = Balancer::new("catalog", &upstreams[..2], &hashed.balance, None);
+    for (user, picked) in users.iter().zip(&first) {
+        if picked != "c" {
+            assert_eq!(without_c.pick(user).authority().host(), picked);
+        }
+    }
+}
+
+#[test]
+fn eject_unhealthy_upstreams() {
+    let upstreams = vec![
+        Upstream::from(Authority::from_static("a:8080")),
+        Upstream::from(Authority::from_static("b:8080")),
+    ];
+    let health: HealthConfig = toml::from_str(
+        r#"
+        consecutive-failures = 2
+        ejection-time = "50ms"
+        slow-start = "0s"
+        probe = { path = "/health", healthy-threshold = 1, unhealthy-threshold = 2 }
+        "#,
+    )
+    .unwrap();
+    let balancer = Balancer::new("catalog", &upstreams, &Balance::RoundRobin, Some(&health));
+    let hosts = |count: usize| -> Vec<String> {
+        let mut hosts: Vec<String> = (0..count)
+            .map(|_| balancer.pick(&json!({})).authority().host().to_owned())
+            .collect();
+        hosts.sort();
+        hosts
+    };
+
+    // Calls failing in a row eject an authority, until its ejection time is up.
+    balancer.record(0, true);
+    balancer.record(0, true);
+    assert_eq!(balancer.status()[0]["state"], "ejected");
+    assert_eq!(hosts(3), ["b", "b", "b"]);
+    std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(hosts(2), ["a", "b"]);
+
+    // Failed probes take an authority out of rotation, and passing ones bring it back.
+    balancer.probed(1, false);
+    assert_eq!(balancer.status()[1]["state"], "healthy");
+    balancer.probed(1, false);
+    assert_eq!(balancer.status()[1]["state"], "unhealthy");
+    assert_eq!(hosts(2), ["a", "a"]);
+    balancer.probed(1, true);
+    assert_eq!(hosts(2), ["a", "b"]);
+
+    // With nothing healthy left, every authority is tried rather than none.
+    balancer.probed(0, false);
+    balancer.probed(0, false);
+    balancer.probed(1, false);
+    balancer.probed(1, false);
+    assert_eq!(hosts(2).len(), 2);
+}
@@ -1,24 +1,36 @@
-use std::io::{Error, ErrorKind, Result};
+use std::{
+    io::{Error, ErrorKind, Result},
+    os::unix::{fs::FileTypeExt, net::UnixStream},
+    path::Path,
+    sync::Arc,
+};
 
-use actix_cors::Cors;
 use actix_web::{middleware::Logger, web::Data, App, HttpServer};
-use delegator_core::{cache::MemoizationCache, config::Configuration};
+use delegator_core::{
+    cache::MemoizationCache, config::Configuration, reload::LiveConfig, tls::CertStore,
+};
 
 use json_adapter::language::TranslateContext;
 
 enum InitErrors {
     MissingConfigFile,
-    ErrorLoadingConfig(std::io::Error),
+    MissingProfile,
+    UnknownSchema(String),
+    Tls(delegator_core::tls::TlsError),
 }
 
 impl From<InitErrors> for Error {
     fn from(err: InitErrors) -> Self {
         match err {
-            InitErrors::MissingConfigFile => Error::new(
-                ErrorKind::Other,
-                "First argument to the server must be a path to the config file",
-            ),
-            InitErrors::ErrorLoadingConfig(err) => Error::new(ErrorKind::Other, err.to_string()),
+            InitErrors::MissingConfigFile => {
+                Error::other("First argument to the server must be a path to the config file")
+            }
+            InitErrors::MissingProfile => Error::other("`--profile` must be followed by a name"),
+            InitErrors::UnknownSchema(name) => Error::other(format!(
+                "Unknown schema {:?}, expected `config` or `cryptogram`",
+                name
+            )),
+            InitErrors::Tls(err) => Error::other(err),
         }
     }
 }
@@ -27,21 +39,60 @@ impl From<InitErrors> for Error {
 async fn main() -> Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
-    let path = std::env::args()
-        .nth(1)
-        .ok_or(InitErrors::MissingConfigFile)?;
+    let mut args = std::env::args().skip(1);
+    let mut profile = std::env::var("DELEGATOR_PROFILE")
+        .ok()
+        .filter(|profile| !profile.is_empty());
+    let mut print_config = false;
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--schema" => {
+                let schema = match args.next().as_deref() {
+                    Some("config") | None => delegator_core::config::schema::configuration(),
+                    Some("cryptogram") => delegator_core::config::schema::cryptogram(),
+                    Some(other) => return Err(InitErrors::UnknownSchema(other.to_owned()).into()),
+                };
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+                return Ok(());
+            }
+            "--profile" => profile = Some(args.next().ok_or(InitErrors::MissingProfile)?),
+            "--print-config" => print_config = true,
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.ok_or(InitErrors::MissingConfigFile)?;
+
+    if print_config {
+        match delegator_core::config::effective(&path, profile.as_deref()) {
+            Ok(effective) => print!("{}", effective),
+            Err(errors) => {
+                eprintln!("{}", errors);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     let Configuration {
         events,
         http,
         services,
         virtualhosts,
-    } = delegator_core::config::load_file(path.as_str()).map_err(InitErrors::ErrorLoadingConfig)?;
+        sources,
+        ..
+    } = match delegator_core::config::load_file(path.as_str(), profile.as_deref()) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            eprintln!("Unable to load {} ({} problems)", path, errors.errors.len());
+            std::process::exit(1);
+        }
+    };
 
     // This is from the Sentry docs, https://docs.sentry.io/platforms/rust/guides/actix-web/
     // I suspect it's so we get error traces in Sentry. We may need to revisit this.
     std::env::set_var("RUST_BACKTRACE", "1");
-    println!("Preparing to bind to {}:{}", http.host, http.port);
-
     // let event_client = {
     //     let client = EventClient::new().await;
     //     Arc::new(client)
@@ -49,34 +100,81 @@ async fn main() -> Result<()> {
 
     let ctx = TranslateContext::build(());
 
-    HttpServer::new(move || {
-        // let allowed_origins = http.cors.clone();
-        let cors = Cors::default()
-            // .allowed_origin_fn(move |origin, _req_head| {
-            //     if let Ok(origin) = origin.to_str() {
-            //         let origin = String::from(origin);
-            //         allowed_origins.contains(&origin)
-            //     } else {
-            //         false
-            //     }
-            // })
-            .allow_any_origin()
-            .allow_any_method()
-            .allow_any_header()
-            .send_wildcard()
-            .max_age(3600);
+    let live = Arc::new(LiveConfig::new(
+        &path,
+        profile,
+        services,
+        virtualhosts,
+        sources,
+    ));
+    delegator_core::reload::watch(live.clone());
+    delegator_core::upstream::watch(live.clone(), http.client.clone());
+
+    let mut servers = Vec::new();
+    for listener in http.listeners() {
+        println!("Preparing to bind to {}", listener);
+        let server = {
+            let (http, events, live, ctx) =
+                (http.clone(), events.clone(), live.clone(), ctx.clone());
+            let listener = listener.clone();
+            HttpServer::new(move || {
+                let listener = listener.clone();
+                App::new()
+                    .wrap(Logger::default().log_target("accesslog"))
+                    .wrap_fn(delegator_core::routes::cors::handle)
+                    .app_data(Data::new(http.cors.clone()))
+                    .app_data(Data::new(events.clone()))
+                    .app_data(Data::new(http.client.clone()))
+                    .app_data(Data::from(live.clone()))
+                    .app_data(Data::new(ctx.clone()))
+                    .app_data(Data::new(MemoizationCache::new()))
+                    .app_data(Data::new(listener.clone()))
+                    .configure(move |server| delegator_core::routes::configure(server, &listener))
+            })
+        };
+
+        let server = match (
+            &listener.socket,
+            &listener.host,
+            listener.port,
+            &listener.tls,
+        ) {
+            (Some(socket), _, _, _) => {
+                remove_stale_socket(socket)?;
+                server.bind_uds(socket)?
+            }
+            (None, Some(host), Some(port), Some(tls)) => {
+                let store = CertStore::load(tls, listener.clone(), live.clone());
+                let store = Arc::new(store.map_err(InitErrors::Tls)?);
+                delegator_core::tls::watch(store.clone());
+                let acceptor = CertStore::acceptor(store).map_err(InitErrors::Tls)?;
+                server.bind_openssl((host.as_str(), port), acceptor)?
+            }
+            (None, Some(host), Some(port), None) => server.bind((host.as_str(), port))?,
+            _ => return Err(Error::other(format!("Unable to bind to {}", listener))),
+        };
+        servers.push(server.run());
+    }
 
-        App::new()
-            .wrap(Logger::default().log_target("accesslog"))
-            .wrap(cors)
-            .app_data(Data::new(events.clone()))
-            .app_data(Data::new(http.client.clone()))
-            .app_data(Data::new(services.clone()))
-            .app_data(Data::new(ctx.clone()))
-            .app_data(Data::new(MemoizationCache::new()))
-            .configure(|server| delegator_core::routes::configure(server, &virtualhosts))
-    })
-    .bind((http.host, http.port))?
-    .run()
-    .await
+    // Servers run side by side, and the first to fail takes the process down with it.
+    futures_util::future::try_join_all(servers).await?;
+    Ok(())
+}
+
+/* A socket file left behind by a previous run would make binding fail. It's only removed once
+ * nothing answers on it, so a second instance can't steal a running one's socket.
+ */
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {}
+        _ => return Ok(()),
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path.display()),
+        )),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(_) => Ok(()),
+    }
 }